@@ -0,0 +1,106 @@
+extern crate clap;
+extern crate screenshot_stuff;
+
+use std::process;
+use std::time::{Duration, Instant};
+use clap::{App, Arg};
+use screenshot_stuff::capture::CaptureError;
+#[cfg(unix)]
+use screenshot_stuff::capture::x11::X11Capture;
+
+/*
+ * Measures what capturing an X display costs with and without DAMAGE, e.g.
+ * on an idle 4K Xvfb:
+ *
+ *   Xvfb :9 -screen 0 3840x2160x24 &
+ *   damagebench --display :9 --seconds 10
+ *
+ * Run something like `xclock -update 1` on the display to see a mostly idle
+ * screen. CPU time is read from /proc/self/stat, so this is Linux only.
+ */
+fn main() {
+    let matches = App::new("damagebench")
+        .about("Measures X11 capture CPU use with and without DAMAGE")
+        .arg(
+            Arg::with_name("display")
+                .long("display")
+                .value_name("DISPLAY")
+                .help("X display to capture (default $DISPLAY)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seconds")
+                .long("seconds")
+                .value_name("N")
+                .help("How long to capture each way (default 10)")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let seconds: u64 = matches
+        .value_of("seconds")
+        .map(|n| n.parse().expect("Invalid --seconds"))
+        .unwrap_or(10);
+    let display = matches.value_of("display");
+
+    for &use_damage in &[false, true] {
+        match run(display, use_damage, Duration::from_secs(seconds)) {
+            Ok((polls, changes, cpu)) => {
+                println!(
+                    "{}: {} polls, {} changes, {:.2} s CPU ({:.1}% of one core)",
+                    if use_damage { "damage" } else { "full frames" },
+                    polls,
+                    changes,
+                    cpu,
+                    cpu * 100.0 / seconds.max(1) as f64
+                );
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+// Polls, changed frames and CPU seconds used.
+#[cfg(unix)]
+fn run(display: Option<&str>, use_damage: bool, length: Duration) -> Result<(u64, u64, f64), String> {
+    use screenshot_stuff::capture::CaptureSource;
+
+    let mut source = X11Capture::new(display, 100, use_damage, false)?;
+    let (mut polls, mut changes) = (0, 0);
+    let cpu_start = cpu_seconds()?;
+    let start = Instant::now();
+    while start.elapsed() < length {
+        polls += 1;
+        match source.capture_frame() {
+            Ok(_) => changes += 1,
+            Err(CaptureError::Timeout) => (),
+            Err(CaptureError::Failed(e)) => return Err(e),
+        }
+    }
+    Ok((polls, changes, cpu_seconds()? - cpu_start))
+}
+
+#[cfg(not(unix))]
+fn run(_display: Option<&str>, _use_damage: bool, _length: Duration) -> Result<(u64, u64, f64), String> {
+    Err("damagebench needs X11".to_owned())
+}
+
+// User and system time of this process.
+#[cfg(unix)]
+fn cpu_seconds() -> Result<f64, String> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut stat = String::new();
+    File::open("/proc/self/stat")
+        .and_then(|mut f| f.read_to_string(&mut stat))
+        .map_err(|e| format!("Error reading /proc/self/stat: {:?}", e))?;
+    // Fields after the command name, which is in brackets and may hold spaces.
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap_or(0) + 1..].split_whitespace().collect();
+    let ticks = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok()).unwrap_or(0);
+    // utime and stime, in clock ticks of (almost always) 1/100 s.
+    Ok((ticks(11) + ticks(12)) as f64 / 100.0)
+}
@@ -2,14 +2,19 @@
 
 extern crate image;
 extern crate rayon;
+extern crate clap;
+extern crate screenshot_stuff;
 
-use std::{env, fs, io, thread};
+use std::{fs, io, thread};
 use std::fs::DirEntry;
 use image::{DynamicImage, GenericImage};
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 
 use rayon::prelude::*;
+use clap::{App, Arg};
+use screenshot_stuff::detect;
+use screenshot_stuff::detect::BlankDetector;
 
 const PIXEL_CUTOFF: u64 = 4;
 
@@ -17,10 +22,28 @@ const PIXEL_CUTOFF: u64 = 4;
  * Compares images in a folder for a slight difference in pixel values
  */
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() >= 2 {
-        let directory = &args[1].to_string();
-        let images = Arc::new(fetch_images(directory).expect("Failed to get images"));
+    let matches = App::new("imgdedupe")
+        .about("Hard links near-identical images in a folder together")
+        .arg(
+            Arg::with_name("directory")
+                .help("Folder of images")
+                .required(true),
+        )
+        .args(&detect::args())
+        .arg(
+            Arg::with_name("remove-blank")
+                .long("remove-blank")
+                .help("Delete blank images instead of only listing them"),
+        )
+        .get_matches();
+
+    {
+        let directory = &matches.value_of("directory").unwrap_or(".").to_string();
+        let mut images = fetch_images(directory).expect("Failed to get images");
+        if let Some(detector) = detect::from_matches(&matches).expect("Invalid blank frame options") {
+            images = filter_blank(images, &detector, matches.is_present("remove-blank"));
+        }
+        let images = Arc::new(images);
         let dupes = find_dupe_indexes(&images);
 
         println!("Dupes: {}", dupes.len());
@@ -30,6 +53,31 @@ fn main() {
     }
 }
 
+// Lists blank images, and when removing them leaves them out of deduping.
+fn filter_blank(images: Vec<ImageInfo>, detector: &BlankDetector, remove: bool) -> Vec<ImageInfo> {
+    images
+        .into_iter()
+        .filter(|image| {
+            match detector.check(&image.pixels, 3, image.width, image.height) {
+                Some(blank) => {
+                    println!("Blank ({}): {}", blank.name(), image.path);
+                    if !remove {
+                        return true;
+                    }
+                    match fs::remove_file(&image.path) {
+                        Ok(_) => false,
+                        Err(e) => {
+                            eprintln!("Can't remove file: {:?}", e);
+                            true
+                        }
+                    }
+                }
+                None => true,
+            }
+        })
+        .collect()
+}
+
 fn link_or_error(img_path_a: &str, img_path_b: &str) {
     println!("Removing {}", img_path_b);
     println!("Linking {} to {}", img_path_a, img_path_b);
@@ -64,7 +112,7 @@ fn find_dupe_indexes(images: &Vec<ImageInfo>) -> Vec<(usize, usize)> {
     let (results_tx_original, results_rx) = channel();
     let work_rx = Arc::new(Mutex::new(work_rx_raw));
 
-    for i in 0..(0 + images.len() / 1 - 1) {
+    for i in 0..images.len().saturating_sub(1) {
         for j in i + 1..images.len() {
             work_tx.send((i, j)).ok();
         }
@@ -1,24 +1,44 @@
 extern crate ctrlc;
 extern crate image;
+extern crate png;
 extern crate time;
-extern crate dxgcap;
 extern crate serde;
 extern crate serde_json;
+extern crate clap;
+extern crate screenshot_stuff;
+#[cfg(unix)]
+extern crate signal_hook;
+#[cfg(all(test, unix))]
+extern crate libc;
 
 use image::{ImageBuffer, Rgba};
+use png::HasParameters;
 use std::path::Path;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
-use dxgcap::DXGIManager;
-use dxgcap::BGRA8;
-use dxgcap::CaptureError;
+use screenshot_stuff::capture;
+use screenshot_stuff::capture::{BGRA8, CaptureError, Cursor, Frame, Rect, SourceOptions};
+use screenshot_stuff::exclude::{Exclusion, Exclusions, RuleAction, RuleField, WindowRule};
+use screenshot_stuff::detect;
+use screenshot_stuff::manifest;
+use screenshot_stuff::filter::FilterChain;
+use screenshot_stuff::hooks::Hooks;
+use screenshot_stuff::poll::PollRate;
+use screenshot_stuff::preview;
+use screenshot_stuff::session::Session;
+use screenshot_stuff::stats;
+use screenshot_stuff::stats::Stats;
+use std::fs;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::io::BufWriter;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::fs::File;
+use clap::{App, Arg, ArgMatches};
 
 #[derive(Clone)]
 struct FrameInfo {
@@ -26,100 +46,658 @@ struct FrameInfo {
     w: usize,
     h: usize,
     frame: Vec<BGRA8>,
+    reason: Reason,
+    cursor: Option<Cursor>,
+    dirty: Option<Vec<Rect>>,
+    // 16 bits per channel RGBA, with --png16 on a deep source.
+    deep: Option<Vec<u16>>,
+}
+
+enum Message {
+    Frame(FrameInfo),
+    // SIGHUP: start a new session folder (time in ms).
+    Rotate(u64),
+}
+
+struct Saver {
+    session: Arc<Mutex<Session>>,
+    // Number of the next screenshot in this session.
+    i: u32,
+    last_saved: Option<Vec<BGRA8>>,
+    // The first settled change has been seen. It is never saved (as before
+    // time-lapse), even if interval frames were saved before it.
+    primed: bool,
+    // `last_saved` is the frame received just before, so the dirty areas of
+    // the next one cover everything that can differ from it.
+    last_is_previous: bool,
+    last_size: (usize, usize),
+    last_path: Option<String>,
+    max_session_bytes: Option<u64>,
+    hooks: Option<Arc<Hooks>>,
+    stats: Arc<Stats>,
+}
+
+impl Saver {
+    // Compares only the dirty areas when they are known to be enough.
+    fn unchanged(&self, buffer: &[BGRA8], w: usize, h: usize, dirty: Option<&Vec<Rect>>) -> bool {
+        match (self.last_saved.as_ref(), dirty) {
+            (Some(last), Some(rects)) if self.last_is_previous && self.last_size == (w, h) => {
+                rects.iter().all(|r| same_within(last, buffer, w, h, r))
+            }
+            (Some(last), _) => last.as_slice() == buffer,
+            (None, _) => false,
+        }
+    }
+
+    // Images are written under a temporary name and only renamed once they
+    // are complete, at the same time as they are added to the timings.
+    // Returns true once the session is over --max-session-size; rotate after
+    // `last_saved` is updated, so the new session starts with this frame.
+    // Errors are already logged.
+    fn save(
+        &mut self,
+        time: u64,
+        w: usize,
+        h: usize,
+        bitflipped: Vec<u8>,
+        deep: Option<Vec<u16>>,
+        tags: Vec<String>,
+    ) -> Result<bool, String> {
+        let frametime = (time as f64) / 1_000.0;
+        let pathname = format!("screenshot{:03}.png", self.i);
+        let (path, partial) = {
+            let mut session = self.session.lock().unwrap();
+            let partial = session.dir.join(format!("screenshot{:03}.part.png", self.i));
+            session.in_progress = Some(partial.clone());
+            (session.dir.join(&pathname), partial)
+        };
+
+        let frametime_string = format_frametime(frametime);
+        let encode_start = Instant::now();
+        let written = match deep {
+            Some(deep) => save_frame16(&partial, w, h, &deep),
+            None => save_frame(&partial, w, h, bitflipped),
+        };
+        let encode_time = encode_start.elapsed();
+        let saved = written.and_then(|_| {
+            let size = fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+            let mut session = self.session.lock().unwrap();
+            session.in_progress = None;
+            fs::rename(&partial, &path).map_err(|e| format!("{:?}", e))?;
+            let mut entry = vec![frametime_string.clone(), pathname.clone()];
+            entry.extend(tags);
+            session.timings.push(entry);
+            session.bytes += size;
+            Ok(session.bytes)
+        });
+
+        match saved {
+            Ok(session_bytes) => {
+                self.stats.saved.fetch_add(1, Ordering::Relaxed);
+                // Failed saves would skew the ms/frame average.
+                self.stats.record_encode(encode_time);
+                println!(
+                    "Image saved to `{}` @ {} - {} ",
+                    pathname,
+                    frametime_string,
+                    frametime
+                );
+                if let Some(ref hooks) = self.hooks {
+                    let dir = path.parent().unwrap_or(Path::new("."));
+                    hooks.frame_saved(&path, self.i, &frametime_string, dir);
+                }
+                self.last_path = Some(pathname);
+                self.i += 1;
+                // A session always keeps at least two images, so the frame
+                // that starts a new session can't rotate it again.
+                Ok(self.i > 1 && self.max_session_bytes.map_or(false, |max| session_bytes >= max))
+            }
+            Err(e) => {
+                // Leave it out of the timings and carry on; the disk space
+                // check pauses capture if the disk is filling up.
+                eprintln!("Error saving frame @ {} to {:?}: {}", frametime_string, path, e);
+                self.stats.save_errors.fetch_add(1, Ordering::Relaxed);
+                self.session.lock().unwrap().in_progress = None;
+                fs::remove_file(&partial).ok();
+                Err(e)
+            }
+        }
+    }
+
+    // The new session starts with the screen as it is now.
+    fn rotate(&mut self, time: u64) {
+        let (old_dir, frames) = {
+            let mut session = self.session.lock().unwrap();
+            let old = (session.dir.clone(), session.timings.len());
+            session.info.insert("stats".to_owned(), self.stats.to_json());
+            match session.rotate() {
+                Ok(_) => old,
+                Err(e) => {
+                    eprintln!("Error starting a new session: {:?}", e);
+                    return;
+                }
+            }
+        };
+        if let Some(ref hooks) = self.hooks {
+            hooks.session_ended(&old_dir, frames);
+        }
+        self.i = 0;
+        self.last_path = None;
+        if let Some(buffer) = self.last_saved.clone() {
+            let (w, h) = self.last_size;
+            self.save(time, w, h, to_rgba(&buffer), None, vec![manifest::tag("reason", "rotate")]).ok();
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CursorMode {
+    Omit,
+    Composite,
+    // Position stored in timings.json for overlaying later.
+    Record,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Reason {
+    // The screen changed and then settled.
+    Change,
+    // Time-lapse frame, saved whether or not anything changed.
+    Interval,
+}
+
+impl Reason {
+    fn name(&self) -> &'static str {
+        match *self {
+            Reason::Change => "change",
+            Reason::Interval => "interval",
+        }
+    }
+}
+
+fn parse_exclusions(matches: &ArgMatches) -> Exclusions {
+    let mut rules = vec![];
+    let kinds = [
+        ("exclude-title", RuleField::Title, RuleAction::Blackout),
+        ("exclude-class", RuleField::Class, RuleAction::Blackout),
+        ("pause-title", RuleField::Title, RuleAction::Pause),
+        ("pause-class", RuleField::Class, RuleAction::Pause),
+    ];
+    for &(arg, field, action) in kinds.iter() {
+        if let Some(patterns) = matches.values_of(arg) {
+            rules.extend(patterns.map(|p| WindowRule::new(field, action, p)));
+        }
+    }
+    let colour = matches
+        .value_of("exclude-colour")
+        .map(|c| screenshot_stuff::exclude::parse_colour(c).expect("Invalid --exclude-colour"))
+        .unwrap_or(BGRA8 { b: 0, g: 0, r: 0, a: 255 });
+
+    Exclusions {
+        rules: rules,
+        colour: colour,
+    }
 }
 
 fn main() {
+    let window_arg = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("PATTERN")
+            .help(help)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+    };
+    let matches = App::new("keyscreenshot")
+        .about("Saves a screenshot whenever the screen settles after a change")
+        .arg(
+            Arg::with_name("source")
+                .long("source")
+                .value_name("SOURCE")
+                .help("Capture source (dxgi, x11, vnc, http)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .value_name("ADDRESS")
+                .help("What network sources record from (vnc: lab-pc:5900, http: snapshot or MJPEG URL)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("password")
+                .long("password")
+                .value_name("PASSWORD")
+                .help("Password for the vnc source")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-frame-size")
+                .long("max-frame-size")
+                .value_name("MB")
+                .help("Largest image the http source will download (default 32)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("DIR")
+                .help("Folder to save into (default: current folder). SIGHUP starts DIR/sessionNNN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-free")
+                .long("min-free")
+                .value_name("MB")
+                .help("Stop capturing while the output disk has less than MB megabytes free")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("low-space")
+                .long("low-space")
+                .value_name("ACTION")
+                .help("What to do below --min-free: pause (default) until space is freed, or stop")
+                .possible_values(&["pause", "stop"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-session-size")
+                .long("max-session-size")
+                .value_name("MB")
+                .help("Start a new session folder after saving MB megabytes of images")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-session-duration")
+                .long("max-session-duration")
+                .value_name("SECONDS")
+                .help("Start a new session folder after SECONDS seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-save")
+                .long("on-save")
+                .value_name("COMMAND")
+                .help("Shell command run after each image is saved (gets SCREENSHOT_PATH etc.)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-session-end")
+                .long("on-session-end")
+                .value_name("COMMAND")
+                .help("Shell command run when a session folder is finished")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hook-jobs")
+                .long("hook-jobs")
+                .value_name("N")
+                .help("Hook commands allowed to run at once (default 2)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("preview-port")
+                .long("preview-port")
+                .value_name("PORT")
+                .help("Serve a live preview of the recording on http://127.0.0.1:PORT/")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-port")
+                .long("metrics-port")
+                .value_name("PORT")
+                .help("Serve capture statistics for Prometheus on http://127.0.0.1:PORT/metrics")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("status-every")
+                .long("status-every")
+                .value_name("SECONDS")
+                .help("Print a status line every SECONDS seconds (default 60, 0 for never)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-rate")
+                .long("max-rate")
+                .value_name("POLLS")
+                .help("Polls per second while the screen is changing (default 5)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-rate")
+                .long("min-rate")
+                .value_name("POLLS")
+                .help("Polls per second the rate slows to while the screen is still (default 1)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("idle-after")
+                .long("idle-after")
+                .value_name("SECONDS")
+                .help("Start slowing down after the screen has been still this long (default 10)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-damage")
+                .long("no-damage")
+                .help("On X11, compare whole frames instead of using the DAMAGE extension"),
+        )
+        .arg(
+            Arg::with_name("png16")
+                .long("png16")
+                .help("Save 16 bits per channel PNGs when the source has more than 8 (e.g. 30-bit X screens)"),
+        )
+        .arg(window_arg(
+            "exclude-title",
+            "Fill windows whose title matches this glob (e.g. '*KeePass*')",
+        ))
+        .arg(window_arg("exclude-class", "Fill windows whose WM_CLASS matches this glob"))
+        .arg(window_arg("pause-title", "Don't capture while a window with this title is shown"))
+        .arg(window_arg("pause-class", "Don't capture while a window of this class is shown"))
+        .arg(
+            Arg::with_name("exclude-colour")
+                .long("exclude-colour")
+                .value_name("RRGGBB")
+                .help("Colour used to fill excluded windows (default 000000)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("SPEC")
+                .help("Filter applied to frames before comparing and saving, in order given: \
+                       crop=x,y,w,h, scale=N, mask=x,y,w,h[;...], redact[/N]=x,y,w,h[;...] (N pixel blocks, default 16), grayscale")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("filter-file")
+                .long("filter-file")
+                .value_name("FILE")
+                .help("Read filters from FILE, one per line, before any --filter")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .help("Also save a frame every SECONDS seconds, changed or not")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("skip-duplicates")
+                .long("skip-duplicates")
+                .help("Reuse the previous file for unchanged interval frames")
+                .requires("interval"),
+        )
+        .arg(
+            Arg::with_name("cursor")
+                .long("cursor")
+                .value_name("MODE")
+                .help("Leave the pointer out (omit, default), draw it in (composite) or record its position (record)")
+                .possible_values(&["omit", "composite", "record"])
+                .takes_value(true),
+        )
+        .args(&detect::args())
+        .arg(
+            Arg::with_name("blank-action")
+                .long("blank-action")
+                .value_name("ACTION")
+                .help("What to do with blank frames: skip (default) or flag in timings.json")
+                .possible_values(&["skip", "flag"])
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let exclusions = parse_exclusions(&matches);
+    let mut filters = FilterChain::default();
+    if let Some(path) = matches.value_of("filter-file") {
+        filters.load(path).expect("Invalid --filter-file");
+    }
+    for spec in matches.values_of("filter").into_iter().flat_map(|v| v) {
+        filters.push_spec(spec).expect("Invalid --filter");
+    }
+    let interval_ms: Option<u64> = matches.value_of("interval").map(|s| {
+        let seconds: f64 = s.parse().expect("Invalid --interval");
+        (seconds * 1_000.0) as u64
+    });
+    let skip_duplicates = matches.is_present("skip-duplicates");
+    let blank_detector = detect::from_matches(&matches).expect("Invalid blank frame options");
+    let skip_blank = matches.value_of("blank-action") != Some("flag");
+    let megabytes = |arg: &str| {
+        matches.value_of(arg).map(|mb| {
+            mb.parse::<u64>().expect(&format!("Invalid --{}", arg)) * 1024 * 1024
+        })
+    };
+    let min_free_bytes = megabytes("min-free");
+    let stop_on_low_space = matches.value_of("low-space") == Some("stop");
+    let max_session_bytes = megabytes("max-session-size");
+    let max_session_ms: Option<u64> = matches.value_of("max-session-duration").map(|s| {
+        s.parse::<u64>().expect("Invalid --max-session-duration") * 1_000
+    });
+    let cursor_mode = match matches.value_of("cursor") {
+        Some("composite") => CursorMode::Composite,
+        Some("record") => CursorMode::Record,
+        _ => CursorMode::Omit,
+    };
+
+    let rate = |arg: &str, default: f64| {
+        matches
+            .value_of(arg)
+            .map(|r| r.parse::<f64>().expect(&format!("Invalid --{}", arg)))
+            .unwrap_or(default)
+    };
+    let mut poll_rate = PollRate::new(
+        rate("max-rate", 5.0),
+        rate("min-rate", 1.0),
+        Duration::from_millis((rate("idle-after", 10.0) * 1_000.0) as u64),
+    );
+
     let one_second = Duration::new(1, 0);
     let one_frame = one_second / 5;
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || { r.store(false, Ordering::SeqCst); })
-        .expect("Error setting Ctrl-C handler");
+    let output = matches.value_of("output").unwrap_or(".");
+    let session = Arc::new(Mutex::new(
+        Session::new(Path::new(output)).expect("Unable to create output directory"),
+    ));
+    session
+        .lock()
+        .unwrap()
+        .set_setting("filters", serde_json::to_value(filters.describe()).unwrap());
 
-    let mut manager = DXGIManager::new(200).expect("Unable to make manager.");
-    manager.set_capture_source_index(0);
+    let capture_stats = Arc::new(Stats::new());
+    if let Some(port) = matches.value_of("metrics-port") {
+        let port = port.parse().expect("Invalid --metrics-port");
+        stats::serve(port, capture_stats.clone()).expect("Unable to start metrics server");
+    }
+    let status_every_ms: u64 = matches
+        .value_of("status-every")
+        .map(|s| s.parse::<u64>().expect("Invalid --status-every") * 1_000)
+        .unwrap_or(60_000);
+
+    if let Some(port) = matches.value_of("preview-port") {
+        let port = port.parse().expect("Invalid --preview-port");
+        preview::start(port, session.clone()).expect("Unable to start preview server");
+    }
+
+    let (running, hangup) = handle_signals(session.clone());
+
+    let source_name = matches.value_of("source").unwrap_or(capture::default_source());
+    let source_options = SourceOptions {
+        timeout_ms: {
+            let fastest = poll_rate.fastest();
+            fastest.as_secs() as u32 * 1_000 + fastest.subsec_nanos() / 1_000_000
+        },
+        use_damage: !matches.is_present("no-damage"),
+        target: matches.value_of("target").map(|t| t.to_owned()),
+        password: matches.value_of("password").map(|p| p.to_owned()),
+        keep_deep: matches.is_present("png16"),
+        max_frame_bytes: megabytes("max-frame-size").unwrap_or(32 * 1024 * 1024) as usize,
+    };
+    if source_options.keep_deep && !filters.is_empty() {
+        eprintln!("--png16 has no effect with --filter; saving 8 bit PNGs");
+    }
+    let mut source = capture::open(source_name, &source_options).expect("Unable to open capture source.");
     //manager.acquire_output_duplication();
 
     //let pixels = w * h * 4;
     //let (w2, h2) = (&w, *h);
 
+    let hooks = if matches.is_present("on-save") || matches.is_present("on-session-end") {
+        let jobs = matches
+            .value_of("hook-jobs")
+            .map(|j| j.parse().expect("Invalid --hook-jobs"))
+            .unwrap_or(2);
+        Some(Arc::new(Hooks::new(
+            matches.value_of("on-save").map(|c| c.to_owned()),
+            matches.value_of("on-session-end").map(|c| c.to_owned()),
+            jobs,
+            session.clone(),
+        )))
+    } else {
+        None
+    };
+
     // Setup threads
-    let (tx_all, rx_all): (Sender<FrameInfo>, Receiver<FrameInfo>) = mpsc::channel();
+    let (tx_all, rx_all): (Sender<Message>, Receiver<Message>) = mpsc::channel();
+    let saver_session = session.clone();
+    let saver_stats = capture_stats.clone();
     let handle = thread::spawn(move || {
-        let mut i = 0;
-
-        let mut last_saved: Option<Vec<BGRA8>> = None;
+        let mut saver = Saver {
+            session: saver_session,
+            i: 0,
+            last_saved: None,
+            primed: false,
+            last_is_previous: false,
+            last_size: (0, 0),
+            last_path: None,
+            max_session_bytes: max_session_bytes,
+            hooks: hooks,
+            stats: saver_stats,
+        };
 
         let mut ignored = false;
         //ctrlc::set_handler(move || {} ).expect("Error setting ctrlc handler");
 
-        let mut timings: Vec<Vec<String>> = vec![];
-
-        for frameinfo in rx_all {
+        for message in rx_all {
+            let frameinfo = match message {
+                Message::Frame(frameinfo) => frameinfo,
+                Message::Rotate(time) => {
+                    saver.rotate(time);
+                    continue;
+                }
+            };
 
             let frametime = (frameinfo.time as f64) / 1_000.0;
             let w = frameinfo.w;
             let h = frameinfo.h;
             let buffer = frameinfo.frame;
 
-            last_saved = match last_saved {
-                None => Some(buffer),
-                Some(last_saved) => {
-                    if last_saved == buffer {
-                        println!("Ignored frame");
-                        Some(last_saved)
-                    } else {
-                        let mut bitflipped = Vec::with_capacity(w * h * 4);
-                        for pixel in &buffer {
-                            //let (b, g, r, a) = (pixel[0], pixel[1], pixel[2], pixel[3]);
-                            //bitflipped.extend_from_slice(&[r, g, b, a]);
-                            bitflipped.extend_from_slice(&[pixel.r, pixel.g, pixel.b, pixel.a])
+            let reason_tag = manifest::tag("reason", frameinfo.reason.name());
+            let cursor_tag = match (cursor_mode, &frameinfo.cursor) {
+                (CursorMode::Record, &Some(ref cursor)) => {
+                    vec![manifest::tag("cursor", &format!("{},{}", cursor.x, cursor.y))]
+                }
+                _ => vec![],
+            };
+            let dirty_tag = match frameinfo.dirty {
+                Some(ref rects) => vec![manifest::tag("dirty", &manifest::format_rects(rects))],
+                None => vec![],
+            };
+
+            let mut session_full = false;
+            match frameinfo.reason {
+                Reason::Change if !saver.primed => {
+                    saver.primed = true;
+                    // Interval frames compare with what was last saved.
+                    if saver.last_saved.is_some() {
+                        saver.last_is_previous = false;
+                        continue;
+                    }
+                }
+                Reason::Change if saver.unchanged(&buffer, w, h, frameinfo.dirty.as_ref()) => {
+                    println!("Ignored frame");
+                    saver.stats.skipped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                Reason::Interval
+                    if skip_duplicates && saver.last_path.is_some() && saver.unchanged(&buffer, w, h, None) =>
+                {
+                    let pathname = saver.last_path.clone().unwrap_or_default();
+                    let frametime_string = format_frametime(frametime);
+                    println!("Unchanged at {}, reusing `{}`", frametime_string, pathname);
+                    let mut entry = vec![
+                        frametime_string,
+                        pathname,
+                        reason_tag,
+                        manifest::tag("duplicate", "1"),
+                    ];
+                    entry.extend(cursor_tag);
+                    saver.session.lock().unwrap().timings.push(entry);
+                    saver.stats.skipped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                _ => {
+                    // The pointer is only drawn into the 8 bit image.
+                    let (bitflipped, deep) = match (cursor_mode, &frameinfo.cursor) {
+                        (CursorMode::Composite, &Some(ref cursor)) => {
+                            let mut with_cursor = buffer.clone();
+                            capture::composite_cursor(&mut with_cursor, w, h, cursor);
+                            (to_rgba(&with_cursor), None)
+                        }
+                        _ => (to_rgba(&buffer), frameinfo.deep),
+                    };
+                    let blank = blank_detector
+                        .as_ref()
+                        .and_then(|d| d.check(&bitflipped, 4, w as u32, h as u32));
+                    let mut entry_tags = vec![reason_tag];
+                    entry_tags.extend(cursor_tag);
+                    entry_tags.extend(dirty_tag);
+                    if let Some(blank) = blank {
+                        if skip_blank {
+                            println!("Skipped blank frame ({})", blank.name());
+                            saver.stats.skipped.fetch_add(1, Ordering::Relaxed);
+                            saver.last_is_previous = false;
+                            continue;
                         }
+                        entry_tags.push(manifest::tag("blank", &blank.name()));
+                    }
 
-                        let pathname = format!("screenshot{:03}.png", i);
-                        let path = Path::new(&pathname);
-
-                        let image: ImageBuffer<Rgba<u8>, _> =
-                            ImageBuffer::from_raw(w as u32, h as u32, bitflipped)
-                                .expect("Couldn't convert frame into image buffer.");
-
-                        image.save(&path).expect(&format!(
-                            "Couldn't save image to `screenshot{}.png`.",
-                            i
-                        ));
-
-                        let frametime_hours = frametime as u32 / 3600;
-                        let frametime_minutes = (frametime as u32 % 3600) / 60;
-                        let frametime_seconds = frametime % 60 as f64;
-                        let frametime_string = format!(
-                            "{:02}:{:02}:{:06.3}",
-                            frametime_hours,
-                            frametime_minutes,
-                            frametime_seconds
-                        );
-                        println!(
-                            "Image saved to `{}` @ {} - {} ",
-                            pathname,
-                            frametime_string,
-                            frametime
-                        );
-
-                        timings.push(vec![frametime_string, pathname.clone()]);
-
-                        i += 1;
-
-                        Some(buffer)
+                    match saver.save(frameinfo.time, w, h, bitflipped, deep, entry_tags) {
+                        Ok(full) => session_full = full,
+                        // Still compared with the last frame written, so
+                        // the same frame is tried again.
+                        Err(_) => {
+                            saver.last_is_previous = false;
+                            continue;
+                        }
                     }
                 }
-            };
+            }
+            saver.last_saved = Some(buffer);
+            saver.last_is_previous = true;
+            saver.last_size = (w, h);
+            if session_full {
+                saver.rotate(frameinfo.time);
+            }
         }
         println!("Finishing up there...");
-        let mut timings_file = File::create("timings.json");
-        match timings_file {
-            Ok(f) => serde_json::to_writer(f, &timings).expect("Error writing timings file"),
-            Err(e) => println!("Error creating timings file"),
+        let (dir, frames) = {
+            let mut session = saver.session.lock().unwrap();
+            session.info.insert("stats".to_owned(), saver.stats.to_json());
+            match session.write_manifest() {
+                Ok(_) => (),
+                Err(e) => println!("Error writing timings file: {:?}", e),
+            };
+            (session.dir.clone(), session.timings.len())
         };
+        if let Some(ref hooks) = saver.hooks {
+            hooks.session_ended(&dir, frames);
+            println!("Waiting for hooks...");
+            hooks.join();
+        }
         //buffer.write(b"some bytes")?;
         println!("Finished up there...");
     });
@@ -131,19 +709,102 @@ fn main() {
         let base_epoch = time::precise_time_ns();
 
         let mut frameinfo_last: Option<FrameInfo> = None;
+        let mut frameinfo_latest: Option<FrameInfo> = None;
+        let mut next_interval: u64 = 0;
+        let mut paused_by: Option<String> = None;
+        // A frame was dropped, so the dirty areas no longer add up.
+        let mut dirty_lost = false;
+        let mut session_started: u64 = 0;
+        let mut next_space_check: u64 = 0;
+        let mut low_space = false;
+        let mut next_status: u64 = status_every_ms;
         for _ in 0..200 {
             if !running.load(Ordering::SeqCst) {
                 break;
             }
             while running.load(Ordering::SeqCst) {
-                let (buffer, w, h) = match manager.capture_frame() {
-                    Ok((buffer, (w, h))) => (buffer, w, h),
+                let now = (time::precise_time_ns() - base_epoch) / 1_000_000;
+                let session_expired = max_session_ms.map_or(false, |max| now - session_started >= max);
+                if hangup.swap(false, Ordering::SeqCst) || session_expired {
+                    tx1.send(Message::Rotate(now)).expect("Error sending raw image data.");
+                    session_started = now;
+                }
+
+                if let (Some(min_free), true) = (min_free_bytes, now >= next_space_check) {
+                    next_space_check = now + 5_000;
+                    let low = session.lock().unwrap().low_on_space(min_free);
+                    match low {
+                        Ok(Some(free)) => {
+                            if stop_on_low_space {
+                                println!("Only {} MB free, stopping", free / 1024 / 1024);
+                                running.store(false, Ordering::SeqCst);
+                                break;
+                            }
+                            if !low_space {
+                                println!("Only {} MB free, pausing capture", free / 1024 / 1024);
+                                low_space = true;
+                            }
+                        }
+                        Ok(None) => {
+                            if low_space {
+                                println!("Disk space available again, resuming capture");
+                                low_space = false;
+                            }
+                        }
+                        Err(e) => eprintln!("Error checking free disk space: {:?}", e),
+                    }
+                }
+                if status_every_ms > 0 && now >= next_status {
+                    println!("{}", capture_stats.status_line(poll_rate.rate()));
+                    next_status = now + status_every_ms;
+                }
+
+                if low_space {
+                    capture_stats.low_space.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(one_frame);
+                    continue;
+                }
+
+                if let Some(interval) = interval_ms {
+                    let now = (time::precise_time_ns() - base_epoch) / 1_000_000;
+                    if now >= next_interval {
+                        if let Some(ref latest) = frameinfo_latest {
+                            tx1.send(Message::Frame(FrameInfo {
+                                time: now,
+                                reason: Reason::Interval,
+                                cursor: current_cursor(cursor_mode, &mut *source, &filters),
+                                dirty: None,
+                                ..latest.clone()
+                            })).expect("Error sending raw image data.");
+                        }
+                        next_interval = now + interval;
+                    }
+                }
+
+                poll_rate.wait();
+                capture_stats.polls.fetch_add(1, Ordering::Relaxed);
+                let frame = match source.capture_frame() {
+                    Ok(frame) => {
+                        poll_rate.changed();
+                        capture_stats.changes.fetch_add(1, Ordering::Relaxed);
+                        match exclude_windows(&exclusions, &mut *source, frame, &mut paused_by) {
+                            Some(frame) => filters.apply(frame),
+                            None => {
+                                capture_stats.paused.fetch_add(1, Ordering::Relaxed);
+                                dirty_lost = true;
+                                continue;
+                            }
+                        }
+                    }
                     Err(CaptureError::Timeout) => {
-                        match frameinfo_last.clone() {
+                        poll_rate.unchanged();
+                        capture_stats.timeouts.fetch_add(1, Ordering::Relaxed);
+                        match frameinfo_last.take() {
                             None => continue,
-                            Some(frameinfo) => {
-                                tx1.send(frameinfo).expect("Error sending raw image data.");
-                                frameinfo_last = None;
+                            Some(mut frameinfo) => {
+                                frameinfo.cursor = current_cursor(cursor_mode, &mut *source, &filters);
+                                tx1.send(Message::Frame(frameinfo))
+                                    .expect("Error sending raw image data.");
                                 break;
                             }
                         }
@@ -157,17 +818,38 @@ fn main() {
                             panic!("Capture error: {}", error);
                         }*/
                         println!("Error: {:?} -> Sleeping for {:?}", error, one_frame);
+                        capture_stats.errors.fetch_add(1, Ordering::Relaxed);
                         thread::sleep(one_frame);
                         continue;
                     }
                 };
 
+                // Areas changed since the last frame that was sent.
+                let dirty = match (frameinfo_last.take(), frame.dirty) {
+                    _ if dirty_lost => None,
+                    (None, dirty) => dirty,
+                    (Some(FrameInfo { dirty: Some(mut previous), .. }), Some(rects)) => {
+                        previous.extend(rects);
+                        capture::limit_rects(&mut previous, 32);
+                        Some(previous)
+                    }
+                    _ => None,
+                };
+                dirty_lost = false;
+
                 frameinfo_last = Some(FrameInfo {
                     time: (time::precise_time_ns() - base_epoch) / 1_000_000,
-                    w: w,
-                    h: h,
-                    frame: buffer,
+                    w: frame.w,
+                    h: frame.h,
+                    frame: frame.pixels,
+                    reason: Reason::Change,
+                    cursor: None,
+                    dirty: dirty,
+                    deep: frame.deep,
                 });
+                if interval_ms.is_some() {
+                    frameinfo_latest = frameinfo_last.clone();
+                }
 
                 //println!("Captured! Saving...");
 
@@ -182,8 +864,226 @@ fn main() {
             }
 
         }
+
+        // Interrupted before the screen settled: keep what was last seen.
+        if let Some(mut frameinfo) = frameinfo_last.take() {
+            frameinfo.cursor = current_cursor(cursor_mode, &mut *source, &filters);
+            tx1.send(Message::Frame(frameinfo)).expect("Error sending raw image data.");
+        }
     }
     println!("Finishing up here...");
     handle.join().expect("Error finishing up.");
+    println!("{}", capture_stats.status_line(poll_rate.rate()));
     println!("Finished")
 }
+
+// Only asks the source for the pointer when it will be used.
+fn current_cursor(
+    mode: CursorMode,
+    source: &mut dyn capture::CaptureSource,
+    filters: &FilterChain,
+) -> Option<Cursor> {
+    match mode {
+        CursorMode::Omit => None,
+        // Drawn onto the filtered frame, so it is filtered the same way.
+        CursorMode::Composite => source.cursor().map(|cursor| filters.apply_cursor(cursor)),
+        CursorMode::Record => source.cursor().map(|mut cursor| {
+            let (x, y) = filters.map_point(cursor.x, cursor.y);
+            cursor.x = x;
+            cursor.y = y;
+            cursor
+        }),
+    }
+}
+
+fn format_frametime(frametime: f64) -> String {
+    let frametime_hours = frametime as u32 / 3600;
+    let frametime_minutes = (frametime as u32 % 3600) / 60;
+    let frametime_seconds = frametime % 60 as f64;
+    format!(
+        "{:02}:{:02}:{:06.3}",
+        frametime_hours,
+        frametime_minutes,
+        frametime_seconds
+    )
+}
+
+fn to_rgba(buffer: &[BGRA8]) -> Vec<u8> {
+    let mut bitflipped = Vec::with_capacity(buffer.len() * 4);
+    for pixel in buffer {
+        //let (b, g, r, a) = (pixel[0], pixel[1], pixel[2], pixel[3]);
+        //bitflipped.extend_from_slice(&[r, g, b, a]);
+        bitflipped.extend_from_slice(&[pixel.r, pixel.g, pixel.b, pixel.a])
+    }
+    bitflipped
+}
+
+fn save_frame(path: &Path, w: usize, h: usize, bitflipped: Vec<u8>) -> Result<(), String> {
+    let image: ImageBuffer<Rgba<u8>, _> = match ImageBuffer::from_raw(w as u32, h as u32, bitflipped) {
+        Some(image) => image,
+        None => return Err("Couldn't convert frame into image buffer.".to_owned()),
+    };
+
+    image.save(path).map_err(|e| format!("{:?}", e))
+}
+
+fn save_frame16(path: &Path, w: usize, h: usize, rgba: &[u16]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{:?}", e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), w as u32, h as u32);
+    encoder.set(png::ColorType::RGBA).set(png::BitDepth::Sixteen);
+    let mut writer = encoder.write_header().map_err(|e| format!("{:?}", e))?;
+
+    // PNG samples are big endian.
+    let mut data = Vec::with_capacity(rgba.len() * 2);
+    for &v in rgba {
+        data.push((v >> 8) as u8);
+        data.push(v as u8);
+    }
+    writer.write_image_data(&data).map_err(|e| format!("{:?}", e))
+}
+
+// Whether `a` and `b` (both w x h) match inside `rect`.
+fn same_within(a: &[BGRA8], b: &[BGRA8], w: usize, h: usize, rect: &Rect) -> bool {
+    let left = (rect.x.max(0) as usize).min(w);
+    let right = ((rect.x as i64 + rect.w as i64).max(0) as usize).min(w);
+    let top = (rect.y.max(0) as usize).min(h);
+    let bottom = ((rect.y as i64 + rect.h as i64).max(0) as usize).min(h);
+    (top..bottom).all(|y| a[y * w + left..y * w + right.max(left)] == b[y * w + left..y * w + right.max(left)])
+}
+
+// First Ctrl-C/SIGTERM saves what has been captured and stops, the second
+// stops straight away. Returns the running flag and the SIGHUP flag, which
+// starts a new session folder. ctrlc is built without its `termination`
+// feature, which would also stop on SIGHUP.
+fn handle_signals(session: Arc<Mutex<Session>>) -> (Arc<AtomicBool>, Arc<AtomicBool>) {
+    let running = Arc::new(AtomicBool::new(true));
+    let signals = AtomicUsize::new(0);
+    let r = running.clone();
+    let stop = Arc::new(move || {
+        if signals.fetch_add(1, Ordering::SeqCst) == 0 {
+            println!("Finishing the current frame; interrupt again to stop immediately");
+            r.store(false, Ordering::SeqCst);
+            return;
+        }
+        println!("Stopping now");
+        let code = match session.lock() {
+            Ok(mut s) => s.abort().map(|_| 130).unwrap_or_else(|e| {
+                eprintln!("Error writing timings file: {:?}", e);
+                1
+            }),
+            Err(_) => 1,
+        };
+        process::exit(code);
+    });
+    let interrupted = stop.clone();
+    ctrlc::set_handler(move || interrupted()).expect("Error setting Ctrl-C handler");
+
+    let hangup = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        let terminated = signal_hook::iterator::Signals::new(&[signal_hook::SIGTERM])
+            .expect("Error setting SIGTERM handler");
+        thread::spawn(move || for _ in terminated.forever() {
+            stop();
+        });
+        signal_hook::flag::register(signal_hook::SIGHUP, hangup.clone())
+            .expect("Error setting SIGHUP handler");
+    }
+    (running, hangup)
+}
+
+// Blanks excluded windows, or returns None while a pausing window is visible.
+fn exclude_windows(
+    exclusions: &Exclusions,
+    source: &mut dyn capture::CaptureSource,
+    mut frame: Frame,
+    paused_by: &mut Option<String>,
+) -> Option<Frame> {
+    if exclusions.is_empty() {
+        return Some(frame);
+    }
+    let windows = source.windows();
+    match exclusions.apply(&mut frame, &windows) {
+        Exclusion::Pause(title) => {
+            if paused_by.as_ref() != Some(&title) {
+                println!("Paused while `{}` is visible", title);
+                *paused_by = Some(title);
+            }
+            None
+        }
+        Exclusion::Blanked(_) => {
+            if paused_by.take().is_some() {
+                println!("Resumed");
+            }
+            Some(frame)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+    use libc;
+    use screenshot_stuff::capture::BGRA8;
+    use screenshot_stuff::session::Session;
+    use screenshot_stuff::stats::Stats;
+    use super::{handle_signals, to_rgba, Saver};
+
+    fn saver(session: Arc<Mutex<Session>>) -> Saver {
+        Saver {
+            session: session,
+            i: 0,
+            last_saved: None,
+            primed: true,
+            last_is_previous: false,
+            last_size: (0, 0),
+            last_path: None,
+            max_session_bytes: None,
+            hooks: None,
+            stats: Arc::new(Stats::new()),
+        }
+    }
+
+    #[test]
+    fn hangup_starts_a_new_session() {
+        let dir = env::temp_dir().join(format!("keyscreenshot-hangup-{}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let session = Arc::new(Mutex::new(Session::new(&dir).unwrap()));
+        let (running, hangup) = handle_signals(session.clone());
+
+        let frame = vec![BGRA8 { b: 1, g: 2, r: 3, a: 255 }; 4];
+        let mut saver = saver(session.clone());
+        saver.save(0, 2, 2, to_rgba(&frame), None, vec![]).unwrap();
+        saver.last_saved = Some(frame.clone());
+        saver.last_size = (2, 2);
+
+        unsafe {
+            libc::raise(libc::SIGHUP);
+        }
+        // ctrlc would have seen it on its own thread.
+        thread::sleep(Duration::from_millis(200));
+        assert!(running.load(Ordering::SeqCst));
+        assert!(hangup.swap(false, Ordering::SeqCst));
+
+        // As the capture loop does for a hangup.
+        saver.rotate(1_000);
+        saver.save(2_000, 2, 2, to_rgba(&frame), None, vec![]).unwrap();
+        {
+            let session = session.lock().unwrap();
+            assert_eq!(session.dir, dir.join("session002"));
+            assert_eq!(session.timings.len(), 2);
+        }
+        assert!(dir.join("screenshot000.png").exists());
+        assert!(dir.join("timings.json").exists());
+        assert!(dir.join("session002/screenshot000.png").exists());
+        assert!(dir.join("session002/screenshot001.png").exists());
+        assert!(running.load(Ordering::SeqCst));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -8,88 +8,496 @@ extern crate png;
 extern crate twox_hash;
 extern crate itertools;
 extern crate rgb;
+extern crate clap;
+extern crate rayon;
+extern crate threadpool;
+extern crate screenshot_stuff;
 
-use std::{env, fs, thread};
+use std::{fs, process, thread};
 use std::path::Path;
 use std::path::PathBuf;
 use std::fs::File;
 use std::io::Read;
 use std::fs::OpenOptions;
 use std::sync::Arc;
-use image::{ImageFormat, GenericImage, Rgba, Pixel, DynamicImage};
-use image::DynamicImage::ImageRgb8;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+use image::{ImageFormat, GenericImage, DynamicImage, RgbImage};
+use image::DynamicImage::{ImageRgb8, ImageRgba8};
 use std::io::Write;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use twox_hash::XxHash;
 use std::hash::Hasher;
 use png::HasParameters;
 use imagequant::Attributes;
 use rgb::ComponentBytes;
+use clap::{App, Arg};
+use threadpool::ThreadPool;
+use screenshot_stuff::delta;
+use screenshot_stuff::delta::{add2, Selection};
+use screenshot_stuff::capture::Rect;
+use screenshot_stuff::detect;
+use screenshot_stuff::detect::{Blank, BlankDetector};
+use screenshot_stuff::manifest;
+
+struct Options {
+    blank: Option<BlankDetector>,
+    // Drop blank entries from the session rather than flagging them.
+    skip_blank: bool,
+    // No quantizing or JPEG, so every frame rebuilds exactly.
+    lossless: bool,
+    keyframes: Keyframes,
+    // Previous frames, and earlier keyframes, tried as the delta reference.
+    references: usize,
+    reference_keyframes: usize,
+    // Largest per channel error allowed in a rebuilt frame, and below which
+    // pixels count as unchanged.
+    tolerance: Option<u8>,
+    jobs: usize,
+}
+
+// When to store a whole frame instead of a delta. The first frame and
+// frames saved as JPEG are always whole frames.
+struct Keyframes {
+    every_frames: Option<usize>,
+    every_seconds: Option<f64>,
+    // Changed pixels (percent) above which a delta isn't worth it.
+    max_change: Option<u64>,
+}
+
+impl Keyframes {
+    // `last` is the position and time of the last keyframe written.
+    fn due(&self, position: usize, time: f64, last: Option<(usize, f64)>) -> bool {
+        match last {
+            None => true,
+            Some((last_position, last_time)) => {
+                self.every_frames.map_or(false, |n| position - last_position >= n)
+                    || self.every_seconds.map_or(false, |s| time - last_time >= s)
+            }
+        }
+    }
+
+    fn too_different(&self, percent_transparent: u64) -> bool {
+        self.max_change.map_or(false, |max| 100 - percent_transparent > max)
+    }
+}
+
+// Frames that a delta can be made against, by position in the new timings.
+// Entries are diffed as they're queued against the recent frames as read;
+// when written, against what a decoder will rebuild (JPEG and quantizing
+// are lossy), redoing the diff where that differs. So errors don't build up
+// from one delta to the next.
+struct References {
+    recent: VecDeque<(usize, Arc<DynamicImage>)>,
+    // Rebuilt frames of the last few entries written, and whether each is
+    // exactly the frame as read.
+    rebuilt: VecDeque<(usize, Arc<DynamicImage>, bool)>,
+    // Rebuilt keyframes.
+    keyframes: VecDeque<(usize, Arc<DynamicImage>)>,
+    max_recent: usize,
+    max_keyframes: usize,
+}
+
+impl References {
+    fn push_recent(&mut self, position: usize, image: Arc<DynamicImage>) {
+        self.recent.push_back((position, image));
+        if self.recent.len() > self.max_recent {
+            self.recent.pop_front();
+        }
+    }
+
+    fn push_rebuilt(&mut self, position: usize, image: Arc<DynamicImage>, exact: bool, keyframe: bool) {
+        if keyframe && self.max_keyframes > 0 {
+            self.keyframes.push_back((position, image.clone()));
+            if self.keyframes.len() > self.max_keyframes {
+                self.keyframes.pop_front();
+            }
+        }
+        // Nothing after a keyframe applies over frames before it (other
+        // than keyframes), so seeking only decodes from the nearest one.
+        if keyframe {
+            self.rebuilt.clear();
+        }
+        self.rebuilt.push_back((position, image, exact));
+        if self.rebuilt.len() > self.max_recent {
+            self.rebuilt.pop_front();
+        }
+    }
+
+    // Newest first.
+    fn recent(&self) -> Vec<(usize, Arc<DynamicImage>)> {
+        self.recent.iter().rev().cloned().collect()
+    }
+
+    fn rebuilt_frame(&self, position: usize) -> Option<&Arc<DynamicImage>> {
+        let recent = self.rebuilt.iter().find(|r| r.0 == position).map(|r| &r.1);
+        recent.or_else(|| self.keyframes.iter().find(|k| k.0 == position).map(|k| &k.1))
+    }
+}
+
+// An entry read from disk, with blank entries already skipped.
+struct Decoded {
+    image: Arc<DynamicImage>,
+    blank: Option<Blank>,
+}
+
+// An entry diffed and compressed against the recent frames, ready to be
+// written in order.
+struct Prepared {
+    image: Arc<DynamicImage>,
+    blank: Option<Blank>,
+    hash: u64,
+    jpg: Option<Vec<u8>>,
+    // Size of the entry before.
+    previous_size: Option<(u32, u32)>,
+    // Against each recent frame of the same size that isn't too different,
+    // newest first: (position, delta, percent transparent, encoded).
+    deltas: Vec<(usize, Arc<DynamicImage>, u64, Vec<u8>)>,
+}
+
+enum Done {
+    Decoded(usize, Result<Decoded, String>),
+    Prepared(usize, Prepared),
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() == 2 {
-        let mut image_hashes: HashMap<u64, String> = HashMap::new();
-
-        let timings_file_arg = args.get(1).expect("Error getting timings file argument");
-        let (timings_dir, timings) = read_timings(timings_file_arg);
-        let mut timings_new: Vec<Vec<String>> = vec![];
-
-        let images_path = timings_dir.join("images");
-        if !images_path.is_dir() && fs::create_dir(&images_path).is_err() {
-            println!("Error: Can't use 'images' directory");
-            return;
-        }
-
-        let mut previous: Option<DynamicImage> = None;
-        println!("{} entries", timings.len());
-        for entry_num in 0..timings.len() {
-            match timings.get(entry_num) {
-                Some(entry) if entry.len() >= 2 => {
-                    eprintln!("Entry {}", entry_num);
-                    previous = match handle_timings_entry(
-                        entry_num,
-                        entry,
-                        previous,
-                        &mut image_hashes,
-                        &mut timings_new,
-                        &timings_dir,
-                        &images_path,
-                    ) {
-                        Ok((new_previous, new_entry)) => {
-                            timings_new.push(new_entry);
-                            new_previous
-                        }
-                        Err((e, old_previous)) => {
-                            eprintln!("{}", e);
-                            old_previous
-                        }
-                    }
+    let matches = App::new("pngdiff")
+        .about("Rewrites a keyscreenshot session as compressed slide differences")
+        .arg(
+            Arg::with_name("timings")
+                .help("timings.json of the session")
+                .required(true),
+        )
+        .args(&detect::args())
+        .arg(
+            Arg::with_name("blank-action")
+                .long("blank-action")
+                .value_name("ACTION")
+                .help("What to do with blank frames: skip (default) or flag in timings.json")
+                .possible_values(&["skip", "flag"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lossless")
+                .long("lossless")
+                .help("Don't quantize colours or use JPEG"),
+        )
+        .arg(
+            Arg::with_name("keyframe-every")
+                .long("keyframe-every")
+                .value_name("N")
+                .help("Store a whole frame at least every N entries")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keyframe-seconds")
+                .long("keyframe-seconds")
+                .value_name("SECONDS")
+                .help("Store a whole frame at least every SECONDS seconds of recording")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keyframe-change")
+                .long("keyframe-change")
+                .value_name("PERCENT")
+                .help("Store a whole frame when more than PERCENT of the pixels changed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("references")
+                .long("references")
+                .value_name("N")
+                .help("Try the last N frames as the frame to diff against, keeping the smallest (default 1)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reference-keyframes")
+                .long("reference-keyframes")
+                .value_name("N")
+                .help("Also try the last N keyframes (default 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tolerance")
+                .long("tolerance")
+                .value_name("N")
+                .help("Count pixels within N (per channel) of the frame before as unchanged, and \
+                       don't let any rebuilt pixel be further than N from the original")
+                .takes_value(true)
+                .conflicts_with("lossless"),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .value_name("N")
+                .help("Frames to decode and compress at once (default: one per CPU)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .help("Rebuild every frame afterwards and compare it with the original \
+                       (fails if --lossless and any pixel differs)"),
+        )
+        .get_matches();
+
+    let options = Arc::new(Options {
+        blank: detect::from_matches(&matches).expect("Invalid blank frame options"),
+        skip_blank: matches.value_of("blank-action") != Some("flag"),
+        lossless: matches.is_present("lossless"),
+        keyframes: Keyframes {
+            every_frames: matches
+                .value_of("keyframe-every")
+                .map(|n| n.parse().expect("Invalid --keyframe-every")),
+            every_seconds: matches
+                .value_of("keyframe-seconds")
+                .map(|s| s.parse().expect("Invalid --keyframe-seconds")),
+            max_change: matches
+                .value_of("keyframe-change")
+                .map(|p| p.parse().expect("Invalid --keyframe-change")),
+        },
+        references: matches
+            .value_of("references")
+            .map(|n| n.parse::<usize>().expect("Invalid --references").max(1))
+            .unwrap_or(1),
+        reference_keyframes: matches
+            .value_of("reference-keyframes")
+            .map(|n| n.parse().expect("Invalid --reference-keyframes"))
+            .unwrap_or(0),
+        tolerance: matches
+            .value_of("tolerance")
+            .map(|n| n.parse().expect("Invalid --tolerance")),
+        jobs: matches
+            .value_of("jobs")
+            .map(|n| n.parse::<usize>().expect("Invalid --jobs").max(1))
+            .unwrap_or_else(rayon::current_num_threads),
+    });
+
+    let timings_file_arg = matches
+        .value_of("timings")
+        .expect("Error getting timings file argument")
+        .to_owned();
+    let (timings_dir, timings_new, sources) = match rewrite(&timings_file_arg, options.clone()) {
+        Ok(rewritten) => rewritten,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let tolerance = match options.lossless {
+        true => Some(0),
+        false => options.tolerance,
+    };
+    if matches.is_present("verify") {
+        if let Err(e) = verify(&timings_dir, &timings_new, &sources, tolerance) {
+            eprintln!("Verify failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Rewrites the session in place. Returns its folder, the new timings and
+// the original image of each new entry (for verify).
+fn rewrite(timings_path: &str, options: Arc<Options>) -> Result<(PathBuf, Vec<Vec<String>>, Vec<PathBuf>), String> {
+    let mut image_hashes: HashMap<u64, String> = HashMap::new();
+
+    let (timings_dir, timings) = read_timings(timings_path);
+    let mut timings_new: Vec<Vec<String>> = vec![];
+    // Original image of each new entry, for --verify.
+    let mut sources: Vec<PathBuf> = vec![];
+
+    let images_path = timings_dir.join("images");
+    if !images_path.is_dir() && fs::create_dir(&images_path).is_err() {
+        return Err("Can't use 'images' directory".to_owned());
+    }
+    let mut references = References {
+        recent: VecDeque::new(),
+        rebuilt: VecDeque::new(),
+        keyframes: VecDeque::new(),
+        max_recent: options.references,
+        max_keyframes: options.reference_keyframes,
+    };
+    // Position in timings_new and time of the last whole frame.
+    let mut last_keyframe: Option<(usize, f64)> = None;
+    println!("{} entries", timings.len());
+
+    // Entries are decoded, then diffed and compressed, on the pool, a few
+    // ahead of the one being written. Everything that depends on earlier
+    // output (keyframes, hash matches) happens as each is written, in
+    // order, so the result is the same for any number of jobs.
+    let pool = ThreadPool::new(options.jobs);
+    let window = options.jobs * 2;
+    let (sender, receiver) = channel();
+    let mut decoded: HashMap<usize, Result<Decoded, String>> = HashMap::new();
+    let mut prepared: HashMap<usize, Option<Prepared>> = HashMap::new();
+    let (mut next_decode, mut next_prepare, mut next_write) = (0, 0, 0);
+    // Entries queued to be written, which is the next one's position.
+    let mut queued = 0;
+    while next_write < timings.len() {
+        while next_decode < timings.len() && next_decode < next_write + window {
+            let (entry_num, entry) = (next_decode, timings[next_decode].clone());
+            let (timings_dir, options, sender) = (timings_dir.clone(), options.clone(), sender.clone());
+            pool.execute(move || {
+                let result = decode(entry_num, &entry, &timings_dir, &options);
+                sender.send(Done::Decoded(entry_num, result)).unwrap_or(());
+            });
+            next_decode += 1;
+        }
+
+        while let Some(result) = decoded.remove(&next_prepare) {
+            match result {
+                Ok(entry) => {
+                    let entry_num = next_prepare;
+                    let recent = references.recent();
+                    let out_path = images_path.join(format!("slide{:03}.png", entry_num + 1));
+                    references.push_recent(queued, entry.image.clone());
+                    queued += 1;
+                    let (options, sender) = (options.clone(), sender.clone());
+                    pool.execute(move || {
+                        let result = prepare(entry, recent, &out_path, &options);
+                        sender.send(Done::Prepared(entry_num, result)).unwrap_or(());
+                    });
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    prepared.insert(next_prepare, None);
                 }
-                Some(entry) => eprintln!("Entry {} length wrong: {:?}", entry_num, entry),
-                None => eprintln!("Error on entry {}", entry_num),
             }
+            next_prepare += 1;
         }
 
-        println!("Old json: {:?}", timings);
-        println!("New json: {:?}", timings_new);
-        let timings_new_string =
-            serde_json::to_string(&timings_new).expect("Error serialising new timings");
-        let mut rewrite_options = OpenOptions::new();
-        rewrite_options.write(true);
-        rewrite_options.truncate(true);
-        match rewrite_options.open(timings_file_arg).and_then(|mut f| {
-            f.write_all(&timings_new_string.as_bytes())
-        }) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Error writing new json file: {:?}", e),
+        if let Some(ready) = prepared.remove(&next_write) {
+            if let Some(ready) = ready {
+                let entry = &timings[next_write];
+                eprintln!("Entry {}", next_write);
+                let time = manifest::parse_frametime(&entry[0]).unwrap_or(0.0);
+                let keyframe_due = options.keyframes.due(timings_new.len(), time, last_keyframe);
+                let (rebuilt, exact, new_entry) = write_entry(
+                    next_write,
+                    entry,
+                    ready,
+                    &references,
+                    &mut image_hashes,
+                    &timings_dir,
+                    &images_path,
+                    keyframe_due,
+                    &options,
+                );
+                let keyframe = manifest::entry_tag(&new_entry, "keyframe").is_some();
+                if keyframe {
+                    last_keyframe = Some((timings_new.len(), time));
+                }
+                references.push_rebuilt(timings_new.len(), rebuilt, exact, keyframe);
+                timings_new.push(new_entry);
+                sources.push(timings_dir.join(&entry[1]));
+            }
+            next_write += 1;
+            continue;
+        }
+
+        match receiver.recv_timeout(Duration::from_millis(500)) {
+            Ok(Done::Decoded(entry_num, result)) => {
+                decoded.insert(entry_num, result);
+            }
+            Ok(Done::Prepared(entry_num, result)) => {
+                prepared.insert(entry_num, Some(result));
+            }
+            Err(_) if pool.panic_count() > 0 => return Err("A worker thread failed".to_owned()),
+            Err(_) => (),
         }
     }
-    return;
+
+    println!("Old json: {:?}", timings);
+    println!("New json: {:?}", timings_new);
+    let timings_new_string =
+        serde_json::to_string(&timings_new).expect("Error serialising new timings");
+    let mut rewrite_options = OpenOptions::new();
+    rewrite_options.write(true);
+    rewrite_options.truncate(true);
+    match rewrite_options.open(timings_path).and_then(|mut f| {
+        f.write_all(&timings_new_string.as_bytes())
+    }) {
+        Ok(_) => (),
+        Err(e) => eprintln!("Error writing new json file: {:?}", e),
+    }
+
+    Ok((timings_dir, timings_new, sources))
 }
 
-fn read_timings(path: &String) -> (PathBuf, Vec<Vec<String>>) {
+// Rebuilds every frame of the rewritten session and compares it with the
+// image it came from. Returns the largest error in any pixel; fails if a
+// frame can't be rebuilt or that is further than `tolerance`.
+fn verify(
+    timings_dir: &Path,
+    timings_new: &[Vec<String>],
+    sources: &[PathBuf],
+    tolerance: Option<u8>,
+) -> Result<u8, String> {
+    let mut frames_differing = 0;
+    let mut worst_error = 0;
+    let rebuilt = delta::reconstruct(timings_dir, timings_new, &Selection::All, |index, entry, frame| {
+        let source = image::open(&sources[index])
+            .map_err(|e| format!("Error loading {:?}: {:?}", sources[index], e))?
+            .to_rgb();
+        if source.dimensions() != frame.dimensions() {
+            return Err(format!(
+                "Entry {} rebuilt as {}x{}, original is {}x{}",
+                index,
+                frame.width(),
+                frame.height(),
+                source.width(),
+                source.height()
+            ));
+        }
+
+        let mut max_error = 0;
+        let mut mismatched: u64 = 0;
+        for (a, b) in source.pixels().zip(frame.pixels()) {
+            let error = a.data
+                .iter()
+                .zip(b.data.iter())
+                .map(|(&x, &y)| if x > y { x - y } else { y - x })
+                .max()
+                .unwrap_or(0);
+            if error > 0 {
+                mismatched += 1;
+                max_error = max_error.max(error);
+            }
+        }
+        println!(
+            "Verify entry {} `{}`: max error {}, {} pixels differ",
+            index,
+            entry[1],
+            max_error,
+            mismatched
+        );
+        if mismatched > 0 {
+            frames_differing += 1;
+            worst_error = worst_error.max(max_error);
+        }
+        Ok(())
+    });
+
+    let rebuilt = rebuilt?;
+    println!(
+        "Verified {} frames: {} differ from the originals (max error {})",
+        rebuilt.emitted,
+        frames_differing,
+        worst_error
+    );
+    if !rebuilt.failed.is_empty() {
+        let failed: Vec<String> = rebuilt.failed.iter().map(|&(index, _)| index.to_string()).collect();
+        return Err(format!("entries {} couldn't be rebuilt", failed.join(", ")));
+    }
+    match tolerance {
+        Some(t) if worst_error > t => Err(format!("max error {} is over {}", worst_error, t)),
+        _ => Ok(worst_error),
+    }
+}
+
+fn read_timings(path: &str) -> (PathBuf, Vec<Vec<String>>) {
     let mut timings_file: String = String::new();
     File::open(path)
         .expect("No such file")
@@ -101,46 +509,128 @@ fn read_timings(path: &String) -> (PathBuf, Vec<Vec<String>>) {
     (timings_dir.to_owned(), timings)
 }
 
-fn handle_timings_entry(
-    entry_num: usize,
-    entry: &Vec<String>,
-    previous: Option<DynamicImage>,
-    image_hashes: &mut HashMap<u64, String>,
-    timings_new: &mut Vec<Vec<String>>,
-    timings_dir: &Path,
-    images_path: &Path,
-) -> Result<(Option<DynamicImage>, Vec<String>), (String, Option<DynamicImage>)> {
-    let entry_image = match entry.as_slice() {
+fn decode(entry_num: usize, entry: &[String], timings_dir: &Path, options: &Options) -> Result<Decoded, String> {
+    let entry_image = match entry {
         &[_, ref image, _..] => timings_dir.join(image),
-        _ => return Err((format!("Error with entry {}", entry_num), previous)),
+        _ => return Err(format!("Entry {} length wrong: {:?}", entry_num, entry)),
     };
     let image_data = match image::open(timings_dir.join(&entry_image)) {
         Ok(data) => Arc::new(data),
-        _ => return Err((format!("Error loading img: {:?}", entry_image), previous)),
+        _ => return Err(format!("Error loading img: {:?}", entry_image)),
     };
 
-    let rel_path = {
-        // Generate hash
-        let hasher_thread = img_gen_hash(image_data.as_ref());
+    let blank = options.blank.as_ref().and_then(|detector| {
+        let (w, h) = image_data.dimensions();
+        detector.check(&image_data.to_rgb().into_raw(), 3, w, h)
+    });
+    if let Some(ref blank) = blank {
+        if options.skip_blank {
+            return Err(format!("Skipping blank entry {} ({})", entry_num, blank.name()));
+        }
+    }
 
-        let jpg_thread = img_gen_jpg(image_data.as_ref());
+    Ok(Decoded {
+        image: image_data,
+        blank: blank,
+    })
+}
 
-        let (image_diff, diff_percent) = match &previous {
-            &None => (Arc::clone(&image_data), 0),
-            &Some(ref previous_entry) => diff2(&previous_entry, image_data.as_ref()),
-        };
+// Everything for an entry that only needs the frames before it: the hash,
+// the JPEG and a delta against each recent frame, encoded to `out_path`.
+fn prepare(entry: Decoded, recent: Vec<(usize, Arc<DynamicImage>)>, out_path: &Path, options: &Options) -> Prepared {
+    let image_data = entry.image;
+    let jpg_thread = if options.lossless {
+        None
+    } else {
+        Some(img_gen_jpg(image_data.as_ref()))
+    };
 
-        let hash_value = match hasher_thread.join() {
-            Ok(h) => h,
-            _ => return Err((format!("Error generating hash"), previous)),
-        };
+    let deltas = recent
+        .iter()
+        .filter_map(|&(position, ref reference_image)| {
+            delta_against(position, reference_image, image_data.as_ref(), out_path, options)
+        })
+        .collect();
+
+    Prepared {
+        hash: img_hash(image_data.as_ref()),
+        jpg: jpg_thread.and_then(|t| t.join().ok()).and_then(|jpg| jpg),
+        previous_size: recent.first().map(|&(_, ref image)| image.dimensions()),
+        deltas: deltas,
+        image: image_data,
+        blank: entry.blank,
+    }
+}
 
+// Picks the reference, merges with an identical earlier frame, and writes the
+// entry's image(s). Returns the frame a decoder rebuilds, whether that's
+// exactly the frame as read, and the new timings entry.
+fn write_entry(
+    entry_num: usize,
+    entry: &Vec<String>,
+    prepared: Prepared,
+    references: &References,
+    image_hashes: &mut HashMap<u64, String>,
+    timings_dir: &Path,
+    images_path: &Path,
+    keyframe_due: bool,
+    options: &Options,
+) -> (Arc<DynamicImage>, bool, Vec<String>) {
+    let image_data = prepared.image;
+    let blank = prepared.blank;
+
+    // Frames of another resolution (projector switched, window resized)
+    // can't be diffed against, so the new size starts with a keyframe.
+    let size = image_data.dimensions();
+    let resized = prepared.previous_size.map_or(false, |previous| previous != size);
+    if resized {
+        println!("Entry {} changes size to {}x{}", entry_num, size.0, size.1);
+    }
+
+    let (rel_path, reference, patches_tag, rebuilt) = {
+        // Deltas against earlier keyframes are only made here, once it's
+        // known which entries are keyframes. The smallest delta is kept.
         let out_name = format!("slide{:03}.png", entry_num + 1);
+        let out_path = images_path.join(&out_name);
+        let mut candidates = vec![];
+        if !keyframe_due && !resized {
+            let mut deltas = prepared.deltas;
+            for &(position, ref rebuilt, exact) in references.rebuilt.iter().rev() {
+                if exact {
+                    // Already diffed against the same pixels.
+                    if let Some(i) = deltas.iter().position(|d| d.0 == position) {
+                        candidates.push(deltas.swap_remove(i));
+                    }
+                } else if let Some(candidate) = delta_against(position, rebuilt, &image_data, &out_path, options) {
+                    candidates.push(candidate);
+                }
+            }
+            for &(position, ref keyframe_image) in references.keyframes.iter().rev() {
+                if references.rebuilt.iter().any(|r| r.0 == position) {
+                    continue;
+                }
+                if let Some(candidate) = delta_against(position, keyframe_image, &image_data, &out_path, options) {
+                    candidates.push(candidate);
+                }
+            }
+        }
+        let best = candidates
+            .into_iter()
+            .min_by_key(|&(_, _, _, ref encoded)| encoded.len());
+        let (mut reference, image_diff, diff_percent, encoded) = match best {
+            Some((position, diff, percent, encoded)) => (Some(position), diff, percent, Some(encoded)),
+            None => (None, Arc::new(delta::keyframe(image_data.as_ref())), 0, None),
+        };
+        let hash_value = prepared.hash;
+
+        // Merging rewrites the earlier entry's file. Only lossless output
+        // rebuilds the same afterwards, and later entries were diffed
+        // against it.
         let (name_post_hash, image_post_hash, post_hash_percent, hash_matched) =
-            if image_hashes.contains_key(&hash_value) {
+            if options.lossless && image_hashes.contains_key(&hash_value) {
                 let other_image_path = &image_hashes[&hash_value];
                 match image::open(timings_dir.join(other_image_path)).map(|i| {
-                    ImageRgb8(i.to_rgb())
+                    ImageRgba8(i.to_rgba())
                 }) {
                     Ok(other_image_data) => {
                         let (a, b) = add2(other_image_data, image_diff.as_ref());
@@ -158,45 +648,98 @@ fn handle_timings_entry(
             } else {
                 (out_name, image_diff, diff_percent, false)
             };
-        let out_relpath = match images_path.file_name().and_then(|n| n.to_str()) {
-            Some(images_path_name) => format!("{}/{}", images_path_name, name_post_hash),
-            None => String::new(),
+        let relpath = |path: &Path| match (
+            images_path.file_name().and_then(|n| n.to_str()),
+            path.file_name().and_then(|n| n.to_str()),
+        ) {
+            (Some(images_path_name), Some(name)) => format!("{}/{}", images_path_name, name),
+            _ => String::new(),
         };
         let mut save_filename = images_path.join(name_post_hash);
-        let image_png = save_image(&save_filename, image_post_hash, post_hash_percent);
-        let image_smaller = match jpg_thread.join() {
-            Ok(Some(jpg_data)) => {
-                let jpg_len = jpg_data.len();
-                let png_len = image_png.len();
-
-                if jpg_len * 3 < png_len * 2 {
-                    let old_save_filename = save_filename.clone();
-                    save_filename.set_extension("jpg");
-                    if hash_matched && old_save_filename != save_filename {
-                        for e in timings_new.iter_mut() {
-                            if e.len() >= 2 && Some(&*e[1]).eq(&old_save_filename.to_str()) {
-                                e[1] = save_filename.to_string_lossy().to_string();
-                                fs::remove_file(&old_save_filename).unwrap_or_else(|_| {
-                                    eprintln!("Error removing file")
-                                });
-                            }
-                        }
-                    }
-                    jpg_data
-                } else {
-                    image_png
+        let mut image_png = match encoded {
+            Some(image_png) if !hash_matched => image_png,
+            _ => save_image(&save_filename, image_post_hash.clone(), post_hash_percent, !options.lossless),
+        };
+        // Mostly unchanged deltas are smaller as patches of the changed areas.
+        let mut patches = match (reference, hash_matched) {
+            (Some(_), false) => encode_patches(&image_post_hash, &save_filename, image_png.len(), !options.lossless),
+            _ => None,
+        };
+        let png_len = match patches {
+            Some(ref patches) => patches.iter().map(|&(_, _, ref data)| data.len()).sum(),
+            None => image_png.len(),
+        };
+        let mut jpg = match prepared.jpg {
+            Some(jpg_data) => match jpg_data.len() * 3 < png_len * 2 {
+                true => Some(jpg_data),
+                false => None,
+            },
+            None => None,
+        };
+
+        // What a decoder will get, which the next entries are diffed against
+        // (None if exactly the frame as read). Output rebuilding further than
+        // --tolerance from the original isn't used: then there's no JPEG, and
+        // the delta isn't quantized.
+        let mut rebuilt = None;
+        if !options.lossless {
+            let source = image_data.to_rgb();
+            let within = |frame: &RgbImage| options.tolerance.map_or(true, |t| delta::max_error(&source, frame) <= t);
+            if let Some(frame) = jpg.as_ref().map(|jpg_data| rebuild(None, jpg_data, None)) {
+                match within(&frame) {
+                    true => rebuilt = Some(frame),
+                    false => jpg = None,
+                }
+            }
+            if jpg.is_none() {
+                let base = reference.and_then(|p| references.rebuilt_frame(p)).map(|f| f.to_rgb());
+                let mut frame = rebuild(base.as_ref(), &image_png, patches.as_ref());
+                if !within(&frame) {
+                    image_png = save_image(&save_filename, image_post_hash.clone(), post_hash_percent, false);
+                    patches = match (reference, hash_matched) {
+                        (Some(_), false) => encode_patches(&image_post_hash, &save_filename, image_png.len(), false),
+                        _ => None,
+                    };
+                    frame = rebuild(base.as_ref(), &image_png, patches.as_ref());
                 }
+                rebuilt = Some(frame);
+            }
+            if rebuilt.as_ref().map_or(false, |frame| **frame == *source) {
+                rebuilt = None;
+            }
+        }
+
+        let image_smaller = match jpg {
+            Some(jpg_data) => {
+                // Only lossless output merges, and that never writes JPEG,
+                // so no earlier entry names this file.
+                debug_assert!(!hash_matched);
+                reference = None;
+                patches = None;
+                save_filename.set_extension("jpg");
+                jpg_data
             }
-            _ => image_png,
+            None => image_png,
         };
 
-        match File::create(&save_filename)
-            .expect(&format!("Error writing final image: {:?}", &save_filename))
-            .write(&image_smaller) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Error writing optimised image: {:?}", e),
+        let written: Vec<(&PathBuf, &Vec<u8>)> = match patches {
+            Some(ref patches) => patches.iter().map(|&(ref path, _, ref data)| (path, data)).collect(),
+            None => vec![(&save_filename, &image_smaller)],
+        };
+        for (path, data) in written {
+            match File::create(path)
+                .expect(&format!("Error writing final image: {:?}", path))
+                .write(data) {
+                Ok(_) => (),
+                Err(e) => eprintln!("Error writing optimised image: {:?}", e),
+            }
         }
-        match save_filename.strip_prefix(&timings_dir).ok().and_then(
+        // Patches aren't whole frames, so can't be merged with later ones.
+        let hash_path = match patches {
+            Some(_) => None,
+            None => save_filename.strip_prefix(&timings_dir).ok(),
+        };
+        match hash_path.and_then(
             |p| {
                 p.clone().to_str().to_owned()
             },
@@ -209,29 +752,128 @@ fn handle_timings_entry(
             _ => false,
         };
 
-        out_relpath
+        let patches_tag = patches.map(|patches| {
+            let described: Vec<(String, Rect)> = patches
+                .iter()
+                .map(|&(ref path, rect, _)| (relpath(path), rect))
+                .collect();
+            manifest::format_patches(&described)
+        });
+
+        (relpath(&save_filename), reference, patches_tag, rebuilt)
     };
 
 
     let mut entry_new: Vec<String> = entry.clone();
     entry_new[1] = rel_path.clone();
+    // Left by an earlier pngdiff (of a session rebuilt by undiff), they'd
+    // describe files and positions that aren't this session's.
+    for tag in delta::DELTA_TAGS.iter() {
+        manifest::remove_entry_tag(&mut entry_new, tag);
+    }
+    if let Some(blank) = blank {
+        manifest::set_entry_tag(&mut entry_new, "blank", &blank.name());
+    }
+    if resized {
+        manifest::set_entry_tag(&mut entry_new, "size", &format!("{}x{}", size.0, size.1));
+    }
+    if let Some(patches) = patches_tag {
+        manifest::set_entry_tag(&mut entry_new, "patches", &patches);
+    }
+    match reference {
+        Some(position) => manifest::set_entry_tag(&mut entry_new, "ref", &position.to_string()),
+        None => manifest::set_entry_tag(&mut entry_new, "keyframe", "1"),
+    }
 
-    let output_data = Arc::try_unwrap(image_data).ok().expect(
-        "ARCs should be dropped by now",
-    );
-    Ok((Some(output_data), entry_new))
+    match rebuilt {
+        Some(frame) => (Arc::new(ImageRgb8(frame)), false, entry_new),
+        None => (image_data, true, entry_new),
+    }
 }
 
-fn img_gen_hash(image_in: &DynamicImage) -> thread::JoinHandle<u64> {
-    let image = image_in.clone();
-    thread::spawn(move || {
-        let mut hasher = XxHash::default();
-        for pixel in image.raw_pixels() {
-            hasher.write_u8(pixel);
+// A delta against one earlier frame, encoded to `out_path`, unless that frame
+// is another size or too different.
+fn delta_against(
+    position: usize,
+    reference: &DynamicImage,
+    image: &DynamicImage,
+    out_path: &Path,
+    options: &Options,
+) -> Option<(usize, Arc<DynamicImage>, u64, Vec<u8>)> {
+    if reference.dimensions() != image.dimensions() {
+        return None;
+    }
+    let (diff, percent) = delta::diff_within(reference, image, options.tolerance.unwrap_or(0));
+    if options.keyframes.too_different(percent) {
+        return None;
+    }
+    let encoded = save_image(out_path, diff.clone(), percent, !options.lossless);
+    Some((position, diff, percent, encoded))
+}
+
+// The frame a decoder rebuilds from encoded output (a whole delta, or
+// patches) over `base`.
+fn rebuild(base: Option<&RgbImage>, whole: &[u8], patches: Option<&Vec<(PathBuf, Rect, Vec<u8>)>>) -> RgbImage {
+    let decode = |data: &[u8]| image::load_from_memory(data).expect("Error decoding written image");
+    match (patches, base) {
+        (Some(patches), Some(base)) => {
+            let mut frame = base.clone();
+            for &(_, rect, ref data) in patches {
+                delta::apply_patch(&mut frame, &decode(data), rect.x as u32, rect.y as u32)
+                    .expect("Error rebuilding written image");
+            }
+            frame
         }
-        let hash_value = hasher.finish();
-        hash_value
-    })
+        _ => delta::apply_delta(base, &decode(whole)).expect("Error rebuilding written image"),
+    }
+}
+
+// Patches of the changed areas of a delta, named after `path` (slide005.png,
+// slide005-2.png...), if together they come out smaller than `whole_len`.
+fn encode_patches(
+    delta_image: &DynamicImage,
+    path: &Path,
+    whole_len: usize,
+    quantize: bool,
+) -> Option<Vec<(PathBuf, Rect, Vec<u8>)>> {
+    let rgba = delta_image.to_rgba();
+    let rects = delta::changed_rects(&rgba);
+    let whole = rects.len() == 1 && (rects[0].w, rects[0].h) == rgba.dimensions();
+    if rects.is_empty() || whole {
+        return None;
+    }
+
+    let stem = path.file_stem().and_then(|s| s.to_str())?.to_owned();
+    let mut patches = vec![];
+    let mut total = 0;
+    for (n, rect) in rects.into_iter().enumerate() {
+        let patch_path = match n {
+            0 => path.to_owned(),
+            n => path.with_file_name(format!("{}-{}.png", stem, n + 1)),
+        };
+        let patch = delta::crop(&rgba, &rect);
+        let transparent = patch.pixels().filter(|p| p[3] == 0).count() as u64;
+        let percent = delta::calc_percent_transparent(transparent, rect.w as u64 * rect.h as u64);
+        let data = save_image(&patch_path, Arc::new(ImageRgba8(patch)), percent, quantize);
+        total += data.len();
+        if total >= whole_len {
+            return None;
+        }
+        patches.push((patch_path, rect, data));
+    }
+    Some(patches)
+}
+
+fn img_hash(image: &DynamicImage) -> u64 {
+    let mut hasher = XxHash::default();
+    // The same pixels at another size are a different image.
+    let (w, h) = image.dimensions();
+    hasher.write_u32(w);
+    hasher.write_u32(h);
+    for pixel in image.raw_pixels() {
+        hasher.write_u8(pixel);
+    }
+    hasher.finish()
 }
 
 fn img_gen_jpg(image_in: &DynamicImage) -> thread::JoinHandle<Option<Vec<u8>>> {
@@ -245,27 +887,12 @@ fn img_gen_jpg(image_in: &DynamicImage) -> thread::JoinHandle<Option<Vec<u8>>> {
     })
 }
 
-fn calc_percent_transparent(transparent: u64, total: u64) -> u64 {
-    if total == 0 {
-        return 0;
-    }
-
-    let raw_percent = (transparent * 100) / total;
-
-    match (transparent, raw_percent) {
-        (0, 0) => 0,
-        (_, 0) => 1,
-        (_, n) => n,
-    }
-}
-
 fn save_image(
     out_path: &Path,
     input_image: Arc<DynamicImage>,
     percent_transparent: u64,
+    quantize: bool,
 ) -> Vec<u8> {
-    let trns_black_transparent: [u8; 6] = [0, 0, 0, 0, 0, 0];
-
     let mut oxioptions = oxipng::Options::from_preset(2);
     oxioptions.verbosity = None;
     if percent_transparent < 30 {
@@ -279,51 +906,40 @@ fn save_image(
     let mut image_vec: Vec<u8> = Vec::new();
     let (img_width, img_height) = input_image.dimensions();
     {
-        // With custom convert function
-        let chunk_width = input_image.raw_pixels().len() / (img_height * img_width) as usize;
-        assert!(chunk_width >= 3);
-        let rgba_pixels: Vec<u8> = input_image
-            .raw_pixels()
-            .chunks(chunk_width)
-            .map(|p| match (p[0], p[1], p[2]) {
-                (0, 0, 0) => vec![0, 0, 0, 0],
-                (r, g, b) => vec![r, g, b, 255],
-            })
-            .flat_map(|v| v)
-            .collect();
-        assert_eq!(img_height * img_width * 4, rgba_pixels.len() as u32);
+        // Unchanged pixels are the transparent ones.
+        let rgba_pixels = input_image.to_rgba().into_raw();
 
         // Quantize
-        let (palette, post_quant_image) =
-            do_quantize(&rgba_pixels, img_width as usize, img_height as usize)
-                .unwrap_or((vec![], input_image.raw_pixels()));
+        let quantized = match quantize {
+            true => do_quantize(&rgba_pixels, img_width as usize, img_height as usize),
+            false => None,
+        };
 
         // Encode Image as png
         let mut img_encoder = png::Encoder::new(&mut image_vec, img_width, img_height);
-        let color_type = match palette.len() > 0 {
+        let color_type = match quantized.is_some() {
             true => png::ColorType::Indexed,
-            false => png::ColorType::RGB,
+            false => png::ColorType::RGBA,
         };
         img_encoder.set(color_type).set(png::BitDepth::Eight);
         let mut img_writer = img_encoder.write_header().expect("Problem writing headers");
-        if palette.len() > 0 {
-            match img_writer.write_chunk(png::chunk::PLTE, &palette) {
-                Ok(_) => (),
-                Err(e) => eprintln!("Error writing PLTE header to temporary PNG: {:?}", e),
-            }
-            if percent_transparent != 0 {
-                match img_writer.write_chunk(png::chunk::tRNS, &vec![0]) {
+        let image_data = match quantized {
+            Some((palette, alphas, quantized_pixels)) => {
+                match img_writer.write_chunk(png::chunk::PLTE, &palette) {
                     Ok(_) => (),
-                    Err(e) => eprintln!("Error writing tRNS header to temporary PNG: {:?}", e),
+                    Err(e) => eprintln!("Error writing PLTE header to temporary PNG: {:?}", e),
                 }
+                if alphas.iter().any(|&a| a != 255) {
+                    match img_writer.write_chunk(png::chunk::tRNS, &alphas) {
+                        Ok(_) => (),
+                        Err(e) => eprintln!("Error writing tRNS header to temporary PNG: {:?}", e),
+                    }
+                }
+                quantized_pixels
             }
-        } else if percent_transparent != 0 {
-            match img_writer.write_chunk(png::chunk::tRNS, &trns_black_transparent) {
-                Ok(_) => (),
-                Err(e) => eprintln!("Error writing tRNS header to temporary PNG: {:?}", e),
-            }
-        }
-        match img_writer.write_image_data(&post_quant_image) {
+            None => rgba_pixels,
+        };
+        match img_writer.write_image_data(&image_data) {
             Ok(_) => (),
             Err(e) => {
                 eprintln!("Error writing image data for temporary PNG: {:?}", e);
@@ -338,7 +954,8 @@ fn save_image(
     oxi_output
 }
 
-fn do_quantize(pixels: &Vec<u8>, width: usize, height: usize) -> Option<(Vec<u8>, Vec<u8>)> {
+// Palette colours, palette alphas and indexed pixels.
+fn do_quantize(pixels: &Vec<u8>, width: usize, height: usize) -> Option<(Vec<u8>, Vec<u8>, Vec<u8>)> {
     let mut image_quant = Attributes::new();
     image_quant.set_max_colors(256);
     image_quant.set_quality(70, 100);
@@ -356,7 +973,7 @@ fn do_quantize(pixels: &Vec<u8>, width: usize, height: usize) -> Option<(Vec<u8>
             return None;
         }
     };
-    let (mut palette, quantized_pixels) = match post_quantisation.remapped(&mut quant_image) {
+    let (palette, quantized_pixels) = match post_quantisation.remapped(&mut quant_image) {
         Ok((p, q)) => (p, q),
         _ => {
             eprintln!("Error getting quantized data");
@@ -364,80 +981,387 @@ fn do_quantize(pixels: &Vec<u8>, width: usize, height: usize) -> Option<(Vec<u8>
         }
     };
 
-    // First entry is the transparent one (I hope) and so set it to black.
-    // Allows easy conversion to RGB with #000 transparent value when re-read.
-    if palette[0].as_slice()[3] == 0 {
-        palette[0].as_mut_slice()[0] = 0;
-        palette[0].as_mut_slice()[1] = 0;
-        palette[0].as_mut_slice()[2] = 0;
-    }
-
     let palette_bytes: Vec<u8> = palette
         .iter()
         .flat_map(|p| p.as_slice()[0..3].to_owned())
         .collect();
+    // Deltas only have unchanged (0) and changed (255) pixels; keep the
+    // palette to those so a changed pixel can't come back as unchanged.
+    let alphas: Vec<u8> = palette
+        .iter()
+        .map(|p| if p.as_slice()[3] < 128 { 0 } else { 255 })
+        .collect();
 
-    return Some((palette_bytes, quantized_pixels));
+    return Some((palette_bytes, alphas, quantized_pixels));
 }
 
-fn diff2(imga: &DynamicImage, imgb: &DynamicImage) -> (Arc<DynamicImage>, u64) {
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::Arc;
+    use image;
+    use image::{GenericImage, Rgb, RgbImage};
+    use image::DynamicImage::ImageRgb8;
+    use serde_json;
+    use screenshot_stuff::delta;
+    use screenshot_stuff::delta::{Selection, DELTA_TAGS};
+    use screenshot_stuff::manifest;
+    use super::{rewrite, save_image, verify, Keyframes, Options};
 
-    let (w, h) = imga.dimensions();
+    const BLACK: Rgb<u8> = Rgb { data: [0, 0, 0] };
 
-    let mut imgc = image::DynamicImage::new_rgb8(w, h);
+    // An empty folder of its own for each test, removed when it's dropped.
+    struct Scratch(PathBuf);
 
-    let mut pixels_same: u64 = 0;
-    let mut pixels_notsame: u64 = 0;
-    for y in 0..h {
-        for x in 0..w {
-            let pixel_a = imga.get_pixel(x, y);
-            let pixel_b = imgb.get_pixel(x, y);
+    impl Deref for Scratch {
+        type Target = Path;
 
-            if pixel_a == pixel_b {
-                imgc.put_pixel(x, y, Rgba::from_channels(0, 0, 0, 0));
-                pixels_same += 1;
-            } else {
-                let source_pixel = imgb.get_pixel(x, y);
-                match (source_pixel[0], source_pixel[1], source_pixel[2]) {
-                    (0, 0, 0) => imgc.put_pixel(x, y, Rgba::from_channels(1, 1, 1, 255)),
-                    (r, g, b) => imgc.put_pixel(x, y, Rgba::from_channels(r, g, b, 255)),
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn scratch(name: &str) -> Scratch {
+        let dir = env::temp_dir().join(format!("pngdiff-test-{}-{}", process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).expect("Error making test folder");
+        Scratch(dir)
+    }
+
+    // Slides over a gradient (more colours than a palette holds) with a
+    // block moving across, true black appearing, and a repeat of frame 1.
+    fn frames(noisy: bool) -> Vec<RgbImage> {
+        let mut frames = vec![];
+        for n in 0..7u32 {
+            let n = if n == 4 { 1 } else { n };
+            frames.push(RgbImage::from_fn(64, 48, |x, y| {
+                if x >= n * 8 && x < n * 8 + 12 && y >= 10 && y < 20 {
+                    Rgb([200, 40, 40])
+                } else if n >= 2 && x < 20 && y >= 30 {
+                    BLACK
+                } else if noisy {
+                    let v = (x * 7 + y * 13 + n * 3) as u8;
+                    Rgb([v, v.wrapping_mul(3), x as u8 * 4])
+                } else {
+                    Rgb([(x * 4) as u8, (y * 5) as u8, 128])
                 }
-                pixels_notsame += 1;
-            }
+            }));
         }
+        frames
     }
 
-    (
-        Arc::new(imgc),
-        calc_percent_transparent(pixels_same, pixels_same + pixels_notsame),
-    )
-}
+    // Writes a keyscreenshot session and returns its timings.json.
+    fn session(dir: &Path, frames: &[RgbImage]) -> PathBuf {
+        let mut timings = vec![];
+        for (n, frame) in frames.iter().enumerate() {
+            let name = format!("screenshot{:03}.png", n);
+            frame.save(dir.join(&name)).expect("Error saving test frame");
+            timings.push(vec![format!("00:00:{:06.3}", n as f64), name]);
+        }
+        let path = dir.join("timings.json");
+        fs::write(&path, serde_json::to_string(&timings).unwrap()).expect("Error writing test timings");
+        path
+    }
+
+    fn options(lossless: bool, tolerance: Option<u8>, jobs: usize) -> Arc<Options> {
+        Arc::new(Options {
+            blank: None,
+            skip_blank: true,
+            lossless: lossless,
+            keyframes: Keyframes {
+                every_frames: Some(4),
+                every_seconds: None,
+                max_change: None,
+            },
+            references: 2,
+            reference_keyframes: 1,
+            tolerance: tolerance,
+            jobs: jobs,
+        })
+    }
 
-fn add2(image_base: DynamicImage, image_extra: &DynamicImage) -> (DynamicImage, u64) {
+    // Lossless, with only the given keyframe policies.
+    fn keyframe_options(every_frames: Option<usize>, every_seconds: Option<f64>, max_change: Option<u64>) -> Arc<Options> {
+        Arc::new(Options {
+            blank: None,
+            skip_blank: true,
+            lossless: true,
+            keyframes: Keyframes {
+                every_frames: every_frames,
+                every_seconds: every_seconds,
+                max_change: max_change,
+            },
+            references: 2,
+            reference_keyframes: 1,
+            tolerance: None,
+            jobs: 2,
+        })
+    }
 
-    let (w, h) = image_base.dimensions();
-    let mut image_output = image::DynamicImage::new_rgb8(w, h);
+    // Positions of the entries stored as whole frames.
+    fn keyframes(timings: &[Vec<String>]) -> Vec<usize> {
+        (0..timings.len())
+            .filter(|&n| manifest::entry_tag(&timings[n], "keyframe").is_some())
+            .collect()
+    }
 
-    let mut pixels_transparent: u64 = 0;
-    for y in 0..h {
-        for x in 0..w {
-            let pixel_a = image_base.get_pixel(x, y);
+    // The rewritten session's folder (the scratch folder), timings and sources.
+    fn run(name: &str, frames: &[RgbImage], options: Arc<Options>) -> (Scratch, Vec<Vec<String>>, Vec<PathBuf>) {
+        let dir = scratch(name);
+        let timings = session(&dir, frames);
+        let (_, timings_new, sources) =
+            rewrite(timings.to_str().unwrap(), options).expect("Error rewriting test session");
+        (dir, timings_new, sources)
+    }
 
-            match (pixel_a[0], pixel_a[1], pixel_a[2]) {
-                (0, 0, 0) => {
-                    let pixel_b = image_extra.get_pixel(x, y);
-                    image_output.put_pixel(x, y, pixel_b);
-                    if pixel_a == pixel_b {
-                        pixels_transparent += 1;
-                    }
-                }
-                (_, _, _) => image_output.put_pixel(x, y, image_base.get_pixel(x, y)),
-            }
+    fn files(dir: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<(String, Vec<u8>)> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.is_file())
+            .map(|p| (p.file_name().unwrap().to_string_lossy().into_owned(), fs::read(&p).unwrap()))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn jobs_give_the_same_output() {
+        let frames = frames(true);
+        let (one, _, _) = run("jobs1", &frames, options(false, None, 1));
+        let (four, _, _) = run("jobs4", &frames, options(false, None, 4));
+        assert_eq!(fs::read(one.join("timings.json")).unwrap(), fs::read(four.join("timings.json")).unwrap());
+        let (one_images, four_images) = (files(&one.join("images")), files(&four.join("images")));
+        assert!(!one_images.is_empty());
+        assert!(one_images == four_images, "images differ between --jobs 1 and 4");
+    }
+
+    #[test]
+    fn true_black_survives_quantized_deltas() {
+        let before = ImageRgb8(RgbImage::from_pixel(16, 8, Rgb([90, 90, 90])));
+        let mut after = before.clone();
+        for x in 4..12 {
+            after.put_pixel(x, 3, image::Rgba([0, 0, 0, 255]));
         }
+        let (diff, percent) = delta::diff2(&before, &after);
+        let dir = scratch("black");
+        let png = save_image(&dir.join("slide.png"), diff, percent, true);
+        assert!(png.windows(4).any(|w| w == b"tRNS"), "quantized delta should use tRNS");
+
+        let decoded = image::load_from_memory(&png).unwrap();
+        let rgba = decoded.to_rgba();
+        assert_eq!(rgba.get_pixel(5, 3).data, [0, 0, 0, 255]);
+        assert_eq!(rgba.get_pixel(5, 4).data[3], 0);
+
+        // Merged with a later delta, then applied over the frame before.
+        let later = delta::diff2(&after, &after).0;
+        let (merged, _) = delta::add2(decoded, &later);
+        let rebuilt = delta::apply_delta(Some(&before.to_rgb()), &merged).unwrap();
+        assert!(*rebuilt == *after.to_rgb(), "rebuilt frame differs");
     }
 
-    return (
-        image_output,
-        calc_percent_transparent(pixels_transparent, (w * h * 3) as u64),
-    );
+    #[test]
+    fn verify_fails_on_one_wrong_pixel() {
+        let (dir, timings_new, sources) = run("corrupt", &frames(false), options(true, None, 2));
+        assert_eq!(verify(&dir, &timings_new, &sources, Some(0)), Ok(0));
+
+        // The first entry is a whole frame.
+        let path = dir.join(&timings_new[0][1]);
+        let mut first = image::open(&path).unwrap();
+        let pixel = first.get_pixel(30, 5);
+        first.put_pixel(30, 5, image::Rgba([pixel[0] ^ 1, pixel[1], pixel[2], pixel[3]]));
+        first.to_rgba().save(&path).unwrap();
+        assert!(verify(&dir, &timings_new, &sources, Some(0)).is_err());
+    }
+
+    #[test]
+    fn verify_fails_on_a_corrupt_delta() {
+        let (dir, timings_new, sources) = run("broken", &frames(false), options(true, None, 2));
+        let path = &timings_new[2][1];
+        assert_eq!(timings_new.iter().filter(|e| e[1] == *path).count(), 1);
+        fs::write(dir.join(path), b"not a png").unwrap();
+        let error = verify(&dir, &timings_new, &sources, Some(0)).unwrap_err();
+        assert!(error.contains("couldn't be rebuilt"), "{}", error);
+    }
+
+    #[test]
+    fn tolerance_bounds_lossy_error() {
+        let frames = frames(true);
+        let (dir, timings_new, sources) = run("tolerance", &frames, options(false, Some(4), 2));
+        let worst = verify(&dir, &timings_new, &sources, Some(4)).expect("verify failed");
+        assert!(worst <= 4);
+
+        // Without it the same session does come out further off.
+        let (dir, timings_new, sources) = run("no-tolerance", &frames, options(false, None, 2));
+        assert!(verify(&dir, &timings_new, &sources, None).unwrap() > 4);
+    }
+
+    fn read_timings(path: &Path) -> Vec<Vec<String>> {
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn undiff_round_trip() {
+        let frames = frames(false);
+        let (dir, timings_new, _) = run("roundtrip", &frames, options(true, None, 2));
+
+        // Entries 2 to 5, so positions in the old timings no longer match.
+        let rebuilt = dir.join("rebuilt");
+        fs::create_dir_all(&rebuilt).unwrap();
+        let range = delta::undiff(&dir, &timings_new, &Selection::Range(2.0, 5.0), &rebuilt).unwrap();
+        assert_eq!((range.emitted, range.failed.len()), (4, 0));
+        let timings_path = rebuilt.join("timings.json");
+        let mut timings = read_timings(&timings_path);
+        assert_eq!(timings.len(), 4);
+        for entry in &timings {
+            assert_eq!(entry.len(), 2, "delta tags left in {:?}", entry);
+        }
+
+        // pngdiff replaces tags like these instead of keeping them.
+        manifest::set_entry_tag(&mut timings[2], "ref", "0");
+        manifest::set_entry_tag(&mut timings[2], "patches", "images/slide009.png@0,0,1,1");
+        manifest::set_entry_tag(&mut timings[3], "keyframe", "1");
+        fs::write(&timings_path, serde_json::to_string(&timings).unwrap()).unwrap();
+        let (_, timings_again, sources) = rewrite(timings_path.to_str().unwrap(), options(true, None, 2)).unwrap();
+        assert!(!timings_again[2].iter().any(|t| t.contains("slide009")));
+        assert_eq!(verify(&rebuilt, &timings_again, &sources, Some(0)), Ok(0));
+
+        let again = dir.join("again");
+        fs::create_dir_all(&again).unwrap();
+        assert_eq!(delta::undiff(&rebuilt, &timings_again, &Selection::All, &again).unwrap().emitted, 4);
+        for (n, entry) in read_timings(&again.join("timings.json")).iter().enumerate() {
+            assert!(DELTA_TAGS.iter().all(|&tag| manifest::entry_tag(entry, tag).is_none()));
+            assert_eq!(entry[0], format!("00:00:{:06.3}", (n + 2) as f64));
+            let frame = image::open(again.join(&entry[1])).unwrap().to_rgb();
+            assert!(*frame == *frames[n + 2], "frame {} differs after two round trips", n);
+        }
+    }
+
+    #[test]
+    fn keyframe_policies() {
+        let policy = Keyframes {
+            every_frames: Some(3),
+            every_seconds: Some(2.0),
+            max_change: Some(50),
+        };
+        assert!(policy.due(0, 0.0, None));
+        assert!(!policy.due(2, 1.9, Some((0, 0.0))));
+        assert!(policy.due(3, 1.0, Some((0, 0.0))));
+        assert!(policy.due(1, 2.0, Some((0, 0.0))));
+        assert!(!policy.too_different(50));
+        assert!(policy.too_different(49));
+
+        // No repeated frames, so nothing is merged.
+        let mut frames = frames(false);
+        frames.remove(4);
+        let (_, timings, _) = run("every3", &frames, keyframe_options(Some(3), None, None));
+        assert_eq!(keyframes(&timings), vec![0, 3]);
+        let (_, timings, _) = run("every2s", &frames, keyframe_options(None, Some(2.0), None));
+        assert_eq!(keyframes(&timings), vec![0, 2, 4]);
+
+        // Everything changes at entry 3, and changes back at entry 4.
+        frames[3] = RgbImage::from_fn(64, 48, |x, y| Rgb([255 - x as u8, y as u8, 30]));
+        let (_, timings, _) = run("change", &frames, keyframe_options(None, None, Some(50)));
+        assert_eq!(keyframes(&timings), vec![0, 3, 4]);
+        let (_, timings, _) = run("nopolicy", &frames, keyframe_options(None, None, None));
+        assert_eq!(keyframes(&timings), vec![0]);
+    }
+
+    #[test]
+    fn seeking_starts_at_the_nearest_keyframe() {
+        let mut frames = frames(false);
+        frames.remove(4);
+        let (dir, timings, _) = run("seek", &frames, keyframe_options(Some(3), None, None));
+        assert_eq!(keyframes(&timings), vec![0, 3]);
+        // Entries before the keyframe can't be read any more.
+        for entry in &timings[..3] {
+            fs::write(dir.join(&entry[1]), b"not a png").unwrap();
+        }
+
+        let mut emitted = vec![];
+        let rebuilt = delta::reconstruct(&dir, &timings, &Selection::Index(5), |index, _, frame| {
+            assert!(**frame == *frames[index], "entry {} rebuilt wrong", index);
+            emitted.push(index);
+            Ok(())
+        }).unwrap();
+        assert_eq!((emitted, rebuilt.failed.len()), (vec![5], 0));
+
+        let mut emitted = vec![];
+        let rebuilt = delta::reconstruct(&dir, &timings, &Selection::Range(3.5, 5.0), |index, _, frame| {
+            assert!(**frame == *frames[index], "entry {} rebuilt wrong", index);
+            emitted.push(index);
+            Ok(())
+        }).unwrap();
+        assert_eq!((emitted, rebuilt.failed.len()), (vec![4, 5], 0));
+
+        let rebuilt = delta::reconstruct(&dir, &timings, &Selection::Index(2), |_, _, _| Ok(())).unwrap();
+        assert_eq!((rebuilt.emitted, rebuilt.failed.len()), (0, 1));
+    }
+
+    #[test]
+    fn returning_frame_refers_back_two() {
+        let a = frames(false).remove(0);
+        let b = RgbImage::from_fn(64, 48, |x, y| Rgb([255 - x as u8, y as u8, 30]));
+        let frames = vec![a.clone(), b, a];
+        let (dir, timings_new, sources) = run("aba", &frames, keyframe_options(None, None, None));
+        assert_eq!(keyframes(&timings_new), vec![0]);
+        assert_eq!(manifest::entry_tag(&timings_new[2], "ref"), Some("0"));
+        assert_eq!(verify(&dir, &timings_new, &sources, Some(0)), Ok(0));
+
+        let rebuilt = delta::reconstruct(&dir, &timings_new, &Selection::Index(2), |_, _, frame| {
+            assert!(**frame == *frames[0]);
+            Ok(())
+        }).unwrap();
+        assert_eq!((rebuilt.emitted, rebuilt.failed.len()), (1, 0));
+    }
+
+    #[test]
+    fn small_changes_are_written_as_patches() {
+        let base = RgbImage::from_fn(200, 100, |x, y| Rgb([x as u8, y as u8, 80]));
+        let mut changed = base.clone();
+        for (x, y) in (10..14).flat_map(|x| (5..8).map(move |y| (x, y))) {
+            changed.put_pixel(x, y, Rgb([255, 0, 0]));
+        }
+        for (x, y) in (180..185).flat_map(|x| (90..92).map(move |y| (x, y))) {
+            changed.put_pixel(x, y, Rgb([0, 255, 0]));
+        }
+        let frames = vec![base, changed];
+        let (dir, timings_new, sources) = run("patches", &frames, keyframe_options(None, None, None));
+        let patches = manifest::entry_tag(&timings_new[1], "patches").expect("no patches tag");
+        let rects: Vec<&str> = patches.split(';').map(|p| p.splitn(2, '@').nth(1).unwrap()).collect();
+        assert_eq!(rects, vec!["10,5,4,3", "180,90,5,2"]);
+        assert_eq!(verify(&dir, &timings_new, &sources, Some(0)), Ok(0));
+
+        let rebuilt = delta::reconstruct(&dir, &timings_new, &Selection::Index(1), |_, _, frame| {
+            assert!(**frame == *frames[1]);
+            Ok(())
+        }).unwrap();
+        assert_eq!((rebuilt.emitted, rebuilt.failed.len()), (1, 0));
+    }
+
+    #[test]
+    fn resolution_change_starts_a_keyframe() {
+        let frame = |w, h, n: u32| {
+            RgbImage::from_fn(w, h, |x, y| {
+                let moved = x / 8 == n && y < 8;
+                Rgb([if moved { 255 } else { x as u8 * 3 }, y as u8 * 5, 60])
+            })
+        };
+        let frames: Vec<RgbImage> = (0..6).map(|n| if n < 3 { frame(64, 32, n) } else { frame(48, 40, n) }).collect();
+        let (dir, timings_new, sources) = run("resize", &frames, keyframe_options(None, None, None));
+        assert_eq!(keyframes(&timings_new), vec![0, 3]);
+        assert_eq!(manifest::entry_tag(&timings_new[3], "size"), Some("48x40"));
+        assert!(timings_new[4..].iter().all(|e| manifest::entry_tag(e, "size").is_none()));
+        assert_eq!(verify(&dir, &timings_new, &sources, Some(0)), Ok(0));
+    }
 }
@@ -0,0 +1,103 @@
+extern crate image;
+extern crate serde_json;
+extern crate clap;
+extern crate screenshot_stuff;
+
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::process;
+use clap::{App, Arg};
+use screenshot_stuff::delta;
+use screenshot_stuff::delta::Selection;
+use screenshot_stuff::manifest;
+
+/*
+ * Turns a session rewritten by pngdiff back into full frames, with a
+ * timings.json for them so the output is an ordinary session again.
+ */
+fn main() {
+    let matches = App::new("undiff")
+        .about("Rebuilds full frames from a pngdiff session")
+        .arg(
+            Arg::with_name("timings")
+                .help("timings.json rewritten by pngdiff")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("DIR")
+                .help("Folder for the frames (default: frames next to timings.json)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("index")
+                .long("index")
+                .value_name("N")
+                .help("Only rebuild entry N of timings.json (counting from 0)")
+                .takes_value(true)
+                .conflicts_with_all(&["from", "to"]),
+        )
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .value_name("TIME")
+                .help("Only rebuild entries from this time (hh:mm:ss.sss or seconds)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("to")
+                .long("to")
+                .value_name("TIME")
+                .help("Only rebuild entries up to this time")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let timings_path = Path::new(matches.value_of("timings").expect("Error getting timings file argument"));
+    let timings_dir = timings_path.parent().unwrap_or(Path::new("."));
+    let timings: Vec<Vec<String>> = File::open(timings_path)
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|f| serde_json::from_reader(f).map_err(|e| format!("{:?}", e)))
+        .unwrap_or_else(|e| {
+            eprintln!("Error reading {:?}: {}", timings_path, e);
+            process::exit(1);
+        });
+
+    let time_arg = |arg: &str, default: f64| {
+        matches
+            .value_of(arg)
+            .map(|t| manifest::parse_frametime(t).expect(&format!("Invalid --{}", arg)))
+            .unwrap_or(default)
+    };
+    let selection = match matches.value_of("index") {
+        Some(n) => Selection::Index(n.parse().expect("Invalid --index")),
+        None if matches.is_present("from") || matches.is_present("to") => {
+            Selection::Range(time_arg("from", 0.0), time_arg("to", ::std::f64::INFINITY))
+        }
+        None => Selection::All,
+    };
+
+    let output = match matches.value_of("output") {
+        Some(dir) => Path::new(dir).to_owned(),
+        None => timings_dir.join("frames"),
+    };
+    fs::create_dir_all(&output).expect("Unable to create output directory");
+
+    let result = delta::undiff(timings_dir, &timings, &selection, &output);
+    match result {
+        Ok(ref rebuilt) if !rebuilt.failed.is_empty() => {
+            println!("{} frames rebuilt into {:?}", rebuilt.emitted, output);
+            for &(index, ref e) in &rebuilt.failed {
+                eprintln!("Entry {} couldn't be rebuilt: {}", index, e);
+            }
+            process::exit(1);
+        }
+        Ok(rebuilt) => println!("{} frames rebuilt into {:?}", rebuilt.emitted, output),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
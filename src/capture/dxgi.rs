@@ -0,0 +1,48 @@
+use dxgcap;
+use dxgcap::DXGIManager;
+use super::{BGRA8, CaptureError, CaptureSource, Frame};
+
+// dxgcap doesn't expose the pointer shape, so no cursor is reported. Pointer
+// moves still wake AcquireNextFrame; those come back as timeouts.
+pub struct DxgiCapture {
+    manager: DXGIManager,
+    last: Option<Vec<BGRA8>>,
+}
+
+impl DxgiCapture {
+    pub fn new(timeout_ms: u32) -> Result<DxgiCapture, String> {
+        let mut manager = DXGIManager::new(timeout_ms)
+            .map_err(|e| format!("Unable to make manager: {:?}", e))?;
+        manager.set_capture_source_index(0);
+        Ok(DxgiCapture {
+            manager: manager,
+            last: None,
+        })
+    }
+}
+
+impl CaptureSource for DxgiCapture {
+    fn capture_frame(&mut self) -> Result<Frame, CaptureError> {
+        match self.manager.capture_frame() {
+            Ok((buffer, (w, h))) => {
+                let pixels: Vec<BGRA8> = buffer
+                    .iter()
+                    .map(|p| BGRA8 { b: p.b, g: p.g, r: p.r, a: p.a })
+                    .collect();
+                if self.last.as_ref() == Some(&pixels) {
+                    return Err(CaptureError::Timeout);
+                }
+                self.last = Some(pixels.clone());
+                Ok(Frame {
+                    w: w,
+                    h: h,
+                    pixels: pixels,
+                    dirty: None,
+                    deep: None,
+                })
+            }
+            Err(dxgcap::CaptureError::Timeout) => Err(CaptureError::Timeout),
+            Err(error) => Err(CaptureError::Failed(format!("{:?}", error))),
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use image;
+use image::GenericImage;
+use super::{BGRA8, CaptureError, CaptureSource, Frame};
+use super::pixfmt::{PixelFormat, RawFrame};
+
+// How long to wait for the rest of a response once it has started.
+const RESPONSE_TIMEOUT_SECS: u64 = 10;
+
+/*
+ * Records an HTTP camera/encoder/IP KVM. The URL either serves a single
+ * image (polled once per timeout) or a multipart/x-mixed-replace MJPEG
+ * stream (read part by part). Which one is decided from the Content-Type.
+ */
+pub struct HttpCapture {
+    host: String,
+    path: String,
+    timeout: Duration,
+    max_bytes: usize,
+    stream: Option<MjpegStream>,
+    last: Option<Vec<BGRA8>>,
+}
+
+struct MjpegStream {
+    reader: BufReader<TcpStream>,
+    boundary: String,
+    max_bytes: usize,
+    // The boundary line has already been read (parts without a length).
+    at_headers: bool,
+}
+
+impl MjpegStream {
+    // Reads the next JPEG out of the stream.
+    fn read_part(&mut self) -> io::Result<Vec<u8>> {
+        // Skip to the boundary line ("--boundary", some servers omit the dashes).
+        while !self.at_headers {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(invalid("MJPEG stream ended".to_owned()));
+            }
+            if line.trim().trim_start_matches("--") == self.boundary.trim_start_matches("--") {
+                break;
+            }
+        }
+        self.at_headers = false;
+        let headers = read_headers(&mut self.reader)?;
+        match header(&headers, "content-length").and_then(|l| l.parse().ok()) {
+            Some(length) => read_body(&mut self.reader, Some(length), self.max_bytes),
+            None => {
+                let body = read_until_boundary(&mut self.reader, &self.boundary, self.max_bytes)?;
+                self.at_headers = true;
+                Ok(body)
+            }
+        }
+    }
+}
+
+impl HttpCapture {
+    pub fn new(url: &str, timeout_ms: u32, max_bytes: usize) -> Result<HttpCapture, String> {
+        let without_scheme = if url.starts_with("http://") {
+            &url[7..]
+        } else if url.contains("://") {
+            return Err(format!("Only http:// URLs are supported: {}", url));
+        } else {
+            url
+        };
+        let (host, path) = match without_scheme.find('/') {
+            Some(n) => (&without_scheme[..n], &without_scheme[n..]),
+            None => (without_scheme, "/"),
+        };
+        let host = if host.contains(':') {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+
+        Ok(HttpCapture {
+            host: host,
+            path: path.to_owned(),
+            timeout: Duration::from_millis(timeout_ms as u64),
+            max_bytes: max_bytes,
+            stream: None,
+            last: None,
+        })
+    }
+
+    // Sends the request and reads the headers. Returns the body reader, the
+    // multipart boundary (for streams) and the Content-Length (for images).
+    fn request(&self) -> io::Result<(BufReader<TcpStream>, Option<String>, Option<usize>)> {
+        let stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(Duration::from_secs(RESPONSE_TIMEOUT_SECS)))?;
+        let mut request = stream.try_clone()?;
+        write!(
+            request,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: keyscreenshot\r\n\r\n",
+            self.path,
+            self.host
+        )?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(invalid(format!("HTTP error: {}", status.trim())));
+        }
+        let headers = read_headers(&mut reader)?;
+
+        let boundary = header(&headers, "content-type").and_then(|content_type| {
+            if !content_type.to_lowercase().starts_with("multipart/") {
+                return None;
+            }
+            content_type
+                .split(';')
+                .filter_map(|p| {
+                    let p = p.trim();
+                    if p.to_lowercase().starts_with("boundary=") {
+                        Some(p[9..].trim_matches('"').to_owned())
+                    } else {
+                        None
+                    }
+                })
+                .next()
+        });
+        let length = header(&headers, "content-length").and_then(|l| l.parse().ok());
+        Ok((reader, boundary, length))
+    }
+
+    fn next_image(&mut self) -> Result<Option<Vec<u8>>, CaptureError> {
+        if let Some(mut stream) = self.stream.take() {
+            // Only wait the timeout for the next part to start.
+            stream.reader.get_ref().set_read_timeout(Some(self.timeout)).ok();
+            let closed = match stream.reader.fill_buf() {
+                Ok(b) => b.is_empty(),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                                  e.kind() == io::ErrorKind::TimedOut => {
+                    self.stream = Some(stream);
+                    return Ok(None);
+                }
+                Err(e) => return Err(CaptureError::Failed(format!("{:?}", e))),
+            };
+            if closed {
+                return Err(CaptureError::Failed("MJPEG stream closed".to_owned()));
+            }
+            stream
+                .reader
+                .get_ref()
+                .set_read_timeout(Some(Duration::from_secs(RESPONSE_TIMEOUT_SECS)))
+                .ok();
+            let image = stream
+                .read_part()
+                .map_err(|e| CaptureError::Failed(format!("{:?}", e)))?;
+            self.stream = Some(stream);
+            return Ok(Some(image));
+        }
+
+        let (mut reader, boundary, length) = self.request()
+            .map_err(|e| CaptureError::Failed(format!("{:?}", e)))?;
+        match boundary {
+            Some(boundary) => {
+                let mut stream = MjpegStream {
+                    reader: reader,
+                    boundary: boundary,
+                    max_bytes: self.max_bytes,
+                    at_headers: false,
+                };
+                let image = stream
+                    .read_part()
+                    .map_err(|e| CaptureError::Failed(format!("{:?}", e)))?;
+                self.stream = Some(stream);
+                Ok(Some(image))
+            }
+            None => read_body(&mut reader, length, self.max_bytes)
+                .map(Some)
+                .map_err(|e| CaptureError::Failed(format!("{:?}", e))),
+        }
+    }
+}
+
+impl CaptureSource for HttpCapture {
+    fn capture_frame(&mut self) -> Result<Frame, CaptureError> {
+        let data = match self.next_image()? {
+            Some(data) => data,
+            None => return Err(CaptureError::Timeout),
+        };
+        let img = image::load_from_memory(&data)
+            .map_err(|e| CaptureError::Failed(format!("Can't decode image: {:?}", e)))?;
+        let (w, h) = img.dimensions();
+        let rgba = img.to_rgba().into_raw();
+        let pixels = RawFrame::packed(&rgba, PixelFormat::Rgba8, w as usize, h as usize)
+            .to_frame()
+            .pixels;
+
+        if self.last.as_ref() == Some(&pixels) {
+            // Snapshots have no way to wait for a change.
+            if self.stream.is_none() {
+                thread::sleep(self.timeout);
+            }
+            return Err(CaptureError::Timeout);
+        }
+        self.last = Some(pixels.clone());
+
+        Ok(Frame {
+            w: w as usize,
+            h: h as usize,
+            pixels: pixels,
+            dirty: None,
+            deep: None,
+        })
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Reads `length` bytes, or to the end without one, refusing more than `max`.
+fn read_body<R: Read>(reader: &mut R, length: Option<usize>, max: usize) -> io::Result<Vec<u8>> {
+    let too_big = |length: usize| invalid(format!("Image of {} bytes is over the {} byte limit", length, max));
+    match length {
+        Some(length) if length > max => Err(too_big(length)),
+        Some(length) => {
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body)?;
+            Ok(body)
+        }
+        None => {
+            let mut body = vec![];
+            reader.take(max as u64 + 1).read_to_end(&mut body)?;
+            if body.len() > max {
+                return Err(too_big(body.len()));
+            }
+            Ok(body)
+        }
+    }
+}
+
+fn read_headers<R: BufRead>(reader: &mut R) -> io::Result<Vec<(String, String)>> {
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("Headers ended early".to_owned()));
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if let Some(n) = line.find(':') {
+            headers.push((line[..n].trim().to_lowercase(), line[n + 1..].trim().to_owned()));
+        }
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|h| h.0 == name).map(|h| h.1.as_str())
+}
+
+// For parts without a Content-Length: everything up to the next boundary.
+fn read_until_boundary<R: BufRead>(reader: &mut R, boundary: &str, max: usize) -> io::Result<Vec<u8>> {
+    let marker = format!("\r\n--{}", boundary.trim_start_matches("--"));
+    let mut body = vec![];
+    loop {
+        let mut chunk = vec![];
+        // JPEG data can go a long way without a newline.
+        let limit = (max + marker.len() + 3).saturating_sub(body.len()) as u64;
+        if reader.by_ref().take(limit).read_until(b'\n', &mut chunk)? == 0 {
+            return Err(invalid("MJPEG stream ended".to_owned()));
+        }
+        body.extend(chunk);
+        if body.len() > max + marker.len() + 2 {
+            return Err(invalid(format!("MJPEG part is over the {} byte limit", max)));
+        }
+        if body.ends_with(b"\n") {
+            let trimmed = body.len() - if body.ends_with(b"\r\n") { 2 } else { 1 };
+            if body[..trimmed].ends_with(marker.as_bytes()) {
+                body.truncate(trimmed - marker.len());
+                return Ok(body);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb};
+    use capture::{CaptureError, CaptureSource, grey};
+    use super::HttpCapture;
+
+    fn png(shade: u8) -> Vec<u8> {
+        let mut data = vec![];
+        DynamicImage::ImageRgb8(ImageBuffer::from_pixel(3, 2, Rgb([shade, shade, shade])))
+            .save(&mut data, ImageFormat::PNG)
+            .unwrap();
+        data
+    }
+
+    // Answers one request per response, then waits for the client to hang up.
+    fn serve(responses: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || for response in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            reader.get_mut().write_all(&response).unwrap();
+            reader.read_to_end(&mut vec![]).ok();
+        });
+        format!("http://{}/snapshot.png", address)
+    }
+
+    fn snapshot(body: &[u8], length: usize) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n",
+            length
+        ).into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    fn failure(result: Result<::capture::Frame, CaptureError>) -> String {
+        match result {
+            Err(CaptureError::Failed(e)) => e,
+            Err(e) => panic!("Expected a failure, got {:?}", e),
+            Ok(_) => panic!("Expected a failure, got a frame"),
+        }
+    }
+
+    #[test]
+    fn snapshots() {
+        let url = serve(vec![
+            snapshot(&png(10), png(10).len()),
+            snapshot(&png(10), png(10).len()),
+            snapshot(&png(20), png(20).len()),
+            snapshot(&png(20), 1 << 30),
+        ]);
+        let mut capture = HttpCapture::new(&url, 10, 1 << 20).unwrap();
+        assert_eq!(capture.capture_frame().unwrap().pixels, vec![grey(10); 6]);
+        match capture.capture_frame() {
+            Err(CaptureError::Timeout) => (),
+            _ => panic!("An unchanged snapshot should time out"),
+        }
+        assert_eq!(capture.capture_frame().unwrap().pixels, vec![grey(20); 6]);
+        assert!(failure(capture.capture_frame()).contains("limit"));
+    }
+
+    #[test]
+    fn multipart() {
+        let mut response = b"HTTP/1.0 200 OK\r\n\
+            Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\r\n"
+            .to_vec();
+        // With a length, then without one (up to the next boundary).
+        response.extend_from_slice(format!("--frame\r\nContent-Length: {}\r\n\r\n", png(30).len()).as_bytes());
+        response.extend_from_slice(&png(30));
+        response.extend_from_slice(b"\r\n--frame\r\nContent-Type: image/png\r\n\r\n");
+        response.extend_from_slice(&png(40));
+        response.extend_from_slice(b"\r\n--frame\r\nContent-Length: 1073741824\r\n\r\n");
+
+        let mut capture = HttpCapture::new(&serve(vec![response]), 1_000, 1 << 20).unwrap();
+        assert_eq!(capture.capture_frame().unwrap().pixels, vec![grey(30); 6]);
+        assert_eq!(capture.capture_frame().unwrap().pixels, vec![grey(40); 6]);
+        assert!(failure(capture.capture_frame()).contains("limit"));
+    }
+
+    #[test]
+    fn unbounded_part_is_refused() {
+        let mut response = b"HTTP/1.0 200 OK\r\n\
+            Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\r\n\
+            --frame\r\n\r\n"
+            .to_vec();
+        response.extend(vec![0xffu8; 4096]);
+
+        let mut capture = HttpCapture::new(&serve(vec![response]), 1_000, 1024).unwrap();
+        assert!(failure(capture.capture_frame()).contains("limit"));
+    }
+}
@@ -0,0 +1,175 @@
+/*
+ * Screen capture sources that keyscreenshot can record from.
+ */
+
+#[cfg(windows)]
+pub mod dxgi;
+#[cfg(unix)]
+pub mod x11;
+pub mod vnc;
+pub mod mjpeg;
+pub mod pixfmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct BGRA8 {
+    pub b: u8,
+    pub g: u8,
+    pub r: u8,
+    pub a: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Clone)]
+pub struct Frame {
+    pub w: usize,
+    pub h: usize,
+    pub pixels: Vec<BGRA8>,
+    // Areas changed since the previous frame, when the source knows them.
+    pub dirty: Option<Vec<Rect>>,
+    // RGBA at 16 bits per channel, when the source has more than 8 and
+    // SourceOptions::keep_deep is set.
+    pub deep: Option<Vec<u16>>,
+}
+
+pub struct SourceOptions {
+    pub timeout_ms: u32,
+    // Use X11 DAMAGE to fetch only changed areas.
+    pub use_damage: bool,
+    // Address of network sources (host:port).
+    pub target: Option<String>,
+    pub password: Option<String>,
+    // Keep high bit depth pixels alongside the 8 bit ones.
+    pub keep_deep: bool,
+    // Largest image network sources will accept, so a bad Content-Length
+    // can't use up all memory.
+    pub max_frame_bytes: usize,
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    // Nothing changed before the source gave up waiting.
+    Timeout,
+    Failed(String),
+}
+
+// Pointer image, kept apart from frames so moving it isn't a change.
+#[derive(Clone, Debug)]
+pub struct Cursor {
+    // Hotspot position on screen.
+    pub x: i32,
+    pub y: i32,
+    pub xhot: u32,
+    pub yhot: u32,
+    pub w: u32,
+    pub h: u32,
+    // Premultiplied alpha.
+    pub pixels: Vec<BGRA8>,
+}
+
+// A mapped top-level window, in root window coordinates.
+#[derive(Clone, Debug)]
+pub struct WindowInfo {
+    pub title: String,
+    pub class: String,
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+}
+
+pub trait CaptureSource {
+    fn capture_frame(&mut self) -> Result<Frame, CaptureError>;
+
+    // Sources that can't enumerate windows report none.
+    fn windows(&mut self) -> Vec<WindowInfo> {
+        vec![]
+    }
+
+    fn cursor(&mut self) -> Option<Cursor> {
+        None
+    }
+}
+
+pub fn composite_cursor(pixels: &mut [BGRA8], w: usize, h: usize, cursor: &Cursor) {
+    let left = cursor.x - cursor.xhot as i32;
+    let top = cursor.y - cursor.yhot as i32;
+    for cy in 0..cursor.h as i32 {
+        for cx in 0..cursor.w as i32 {
+            let (x, y) = (left + cx, top + cy);
+            if x < 0 || y < 0 || x as usize >= w || y as usize >= h {
+                continue;
+            }
+            let src = cursor.pixels[(cy * cursor.w as i32 + cx) as usize];
+            let dst = &mut pixels[y as usize * w + x as usize];
+            let blend = |s: u8, d: u8| s.saturating_add((d as u32 * (255 - src.a as u32) / 255) as u8);
+            *dst = BGRA8 {
+                b: blend(src.b, dst.b),
+                g: blend(src.g, dst.g),
+                r: blend(src.r, dst.r),
+                a: dst.a,
+            };
+        }
+    }
+}
+
+// Merges rectangles into their bounding box once there are too many to list.
+pub fn limit_rects(rects: &mut Vec<Rect>, max: usize) {
+    if rects.len() <= max {
+        return;
+    }
+    let left = rects.iter().map(|r| r.x).min().unwrap_or(0);
+    let top = rects.iter().map(|r| r.y).min().unwrap_or(0);
+    let right = rects.iter().map(|r| r.x + r.w as i32).max().unwrap_or(0);
+    let bottom = rects.iter().map(|r| r.y + r.h as i32).max().unwrap_or(0);
+    *rects = vec![Rect {
+        x: left,
+        y: top,
+        w: (right - left) as u32,
+        h: (bottom - top) as u32,
+    }];
+}
+
+// Opaque grey pixel for the capture tests.
+#[cfg(test)]
+pub fn grey(v: u8) -> BGRA8 {
+    BGRA8 {
+        b: v,
+        g: v,
+        r: v,
+        a: 255,
+    }
+}
+
+pub fn default_source() -> &'static str {
+    if cfg!(windows) { "dxgi" } else { "x11" }
+}
+
+pub fn open(name: &str, options: &SourceOptions) -> Result<Box<dyn CaptureSource>, String> {
+    match name {
+        #[cfg(windows)]
+        "dxgi" => dxgi::DxgiCapture::new(options.timeout_ms)
+            .map(|c| Box::new(c) as Box<dyn CaptureSource>),
+        #[cfg(unix)]
+        "x11" => x11::X11Capture::new(None, options.timeout_ms, options.use_damage, options.keep_deep)
+            .map(|c| Box::new(c) as Box<dyn CaptureSource>),
+        "vnc" => {
+            let address = options.target.as_ref().ok_or("The vnc source needs --target host:port")?;
+            vnc::VncCapture::new(address, options.password.as_ref().map(|p| p.as_str()), options.timeout_ms)
+                .map(|c| Box::new(c) as Box<dyn CaptureSource>)
+        }
+        "http" => {
+            let url = options.target.as_ref().ok_or("The http source needs --target URL")?;
+            mjpeg::HttpCapture::new(url, options.timeout_ms, options.max_frame_bytes)
+                .map(|c| Box::new(c) as Box<dyn CaptureSource>)
+        }
+        _ => Err(format!("Unknown or unsupported capture source: {}", name)),
+    }
+}
@@ -0,0 +1,282 @@
+/*
+ * Raw buffers as capture backends hand them over: any of a few pixel
+ * formats, rows possibly padded (stride), and possibly stored rotated.
+ * Everything after capture works on upright, tightly packed BGRA8 frames;
+ * high bit depth formats can also be kept as RGBA16 for 16-bit PNGs.
+ */
+
+use super::{BGRA8, Frame};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    Bgra8,
+    // Fourth byte is padding; treated as opaque.
+    Bgrx8,
+    Rgba8,
+    Rgb8,
+    // 16 bit little endian, 5-6-5.
+    Rgb565,
+    // 32 bit little endian, 2 bits padding then 10 bits each of R, G, B.
+    X2Rgb10,
+    // 16 bits per channel, little endian.
+    Rgba16,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Bgra8 | PixelFormat::Bgrx8 | PixelFormat::Rgba8 => 4,
+            PixelFormat::Rgb8 => 3,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::X2Rgb10 => 4,
+            PixelFormat::Rgba16 => 8,
+        }
+    }
+
+    // More than 8 bits per channel.
+    pub fn is_deep(&self) -> bool {
+        match *self {
+            PixelFormat::X2Rgb10 | PixelFormat::Rgba16 => true,
+            _ => false,
+        }
+    }
+}
+
+// Clockwise rotation of the stored buffer relative to what is on screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+pub struct RawFrame<'a> {
+    pub data: &'a [u8],
+    pub format: PixelFormat,
+    // Size of the buffer as stored (before undoing the rotation).
+    pub width: usize,
+    pub height: usize,
+    // Bytes from the start of one row to the next.
+    pub stride: usize,
+    pub rotation: Rotation,
+}
+
+impl<'a> RawFrame<'a> {
+    pub fn packed(data: &'a [u8], format: PixelFormat, width: usize, height: usize) -> RawFrame<'a> {
+        RawFrame {
+            data: data,
+            format: format,
+            width: width,
+            height: height,
+            stride: width * format.bytes_per_pixel(),
+            rotation: Rotation::None,
+        }
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        let row = self.width * self.format.bytes_per_pixel();
+        if self.stride < row {
+            return Err(format!("Stride {} is less than a row ({} bytes)", self.stride, row));
+        }
+        if self.height > 0 && self.data.len() < self.stride * (self.height - 1) + row {
+            return Err(format!(
+                "Buffer of {} bytes too small for {}x{} {:?}",
+                self.data.len(),
+                self.width,
+                self.height,
+                self.format
+            ));
+        }
+        Ok(())
+    }
+
+    // Size once rotated upright.
+    pub fn upright_size(&self) -> (usize, usize) {
+        match self.rotation {
+            Rotation::None | Rotation::Cw180 => (self.width, self.height),
+            Rotation::Cw90 | Rotation::Cw270 => (self.height, self.width),
+        }
+    }
+
+    pub fn to_frame(&self) -> Frame {
+        let (w, h) = self.upright_size();
+        let pixels = match (self.format, self.rotation) {
+            // The common case: just drop any row padding.
+            (PixelFormat::Bgra8, Rotation::None) | (PixelFormat::Bgrx8, Rotation::None) => {
+                let opaque = self.format == PixelFormat::Bgrx8;
+                let mut pixels = Vec::with_capacity(w * h);
+                for y in 0..h {
+                    let row = &self.data[y * self.stride..y * self.stride + w * 4];
+                    pixels.extend(row.chunks(4).map(|p| {
+                        BGRA8 { b: p[0], g: p[1], r: p[2], a: if opaque { 255 } else { p[3] } }
+                    }));
+                }
+                pixels
+            }
+            _ => {
+                let mut pixels = Vec::with_capacity(w * h);
+                for y in 0..h {
+                    for x in 0..w {
+                        let [r, g, b, a] = self.pixel(x, y);
+                        pixels.push(BGRA8 {
+                            b: (b >> 8) as u8,
+                            g: (g >> 8) as u8,
+                            r: (r >> 8) as u8,
+                            a: (a >> 8) as u8,
+                        });
+                    }
+                }
+                pixels
+            }
+        };
+
+        Frame {
+            w: w,
+            h: h,
+            pixels: pixels,
+            dirty: None,
+            deep: None,
+        }
+    }
+
+    // Upright RGBA with 16 bits per channel.
+    pub fn to_rgba16(&self) -> Vec<u16> {
+        let (w, h) = self.upright_size();
+        let mut pixels = Vec::with_capacity(w * h * 4);
+        for y in 0..h {
+            for x in 0..w {
+                pixels.extend_from_slice(&self.pixel(x, y));
+            }
+        }
+        pixels
+    }
+
+    // RGBA scaled to 16 bits, at an upright position.
+    fn pixel(&self, x: usize, y: usize) -> [u16; 4] {
+        let (sx, sy) = match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Cw90 => (self.width - 1 - y, x),
+            Rotation::Cw180 => (self.width - 1 - x, self.height - 1 - y),
+            Rotation::Cw270 => (y, self.height - 1 - x),
+        };
+        let offset = sy * self.stride + sx * self.format.bytes_per_pixel();
+        let p = &self.data[offset..offset + self.format.bytes_per_pixel()];
+        let widen8 = |v: u8| (v as u16) << 8 | v as u16;
+
+        match self.format {
+            PixelFormat::Bgra8 => [widen8(p[2]), widen8(p[1]), widen8(p[0]), widen8(p[3])],
+            PixelFormat::Bgrx8 => [widen8(p[2]), widen8(p[1]), widen8(p[0]), 0xffff],
+            PixelFormat::Rgba8 => [widen8(p[0]), widen8(p[1]), widen8(p[2]), widen8(p[3])],
+            PixelFormat::Rgb8 => [widen8(p[0]), widen8(p[1]), widen8(p[2]), 0xffff],
+            PixelFormat::Rgb565 => {
+                let v = p[0] as u16 | (p[1] as u16) << 8;
+                let (r, g, b) = (v >> 11, (v >> 5) & 0x3f, v & 0x1f);
+                [
+                    (r << 11) | (r << 6) | (r << 1) | (r >> 4),
+                    (g << 10) | (g << 4) | (g >> 2),
+                    (b << 11) | (b << 6) | (b << 1) | (b >> 4),
+                    0xffff,
+                ]
+            }
+            PixelFormat::X2Rgb10 => {
+                let v = p[0] as u32 | (p[1] as u32) << 8 | (p[2] as u32) << 16 | (p[3] as u32) << 24;
+                let widen10 = |c: u32| ((c << 6) | (c >> 4)) as u16;
+                [
+                    widen10((v >> 20) & 0x3ff),
+                    widen10((v >> 10) & 0x3ff),
+                    widen10(v & 0x3ff),
+                    0xffff,
+                ]
+            }
+            PixelFormat::Rgba16 => [
+                p[0] as u16 | (p[1] as u16) << 8,
+                p[2] as u16 | (p[3] as u16) << 8,
+                p[4] as u16 | (p[5] as u16) << 8,
+                p[6] as u16 | (p[7] as u16) << 8,
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use capture::{BGRA8, grey};
+    use super::{PixelFormat, RawFrame, Rotation};
+
+    #[test]
+    fn padded_stride() {
+        // 3x2 with 4 bytes of junk after each row.
+        let mut data = vec![];
+        for row in 0..2u8 {
+            for x in 0..3u8 {
+                let v = row * 3 + x;
+                data.extend_from_slice(&[v, v, v, 0]);
+            }
+            data.extend_from_slice(&[0xee; 4]);
+        }
+        let expected: Vec<BGRA8> = (0..6).map(grey).collect();
+        for &format in &[PixelFormat::Bgrx8, PixelFormat::Rgba8] {
+            let mut raw = RawFrame::packed(&data, format, 3, 2);
+            raw.stride = 16;
+            raw.check().unwrap();
+            let frame = raw.to_frame();
+            assert_eq!((frame.w, frame.h), (3, 2));
+            let pixels: Vec<BGRA8> = frame.pixels.iter().map(|&p| BGRA8 { a: 255, ..p }).collect();
+            assert_eq!(pixels, expected, "{:?}", format);
+        }
+
+        let mut raw = RawFrame::packed(&data, PixelFormat::Bgra8, 3, 2);
+        raw.stride = 8;
+        assert!(raw.check().is_err());
+        raw.stride = 24;
+        assert!(raw.check().is_err());
+    }
+
+    #[test]
+    fn rotations() {
+        // Stored 3 wide and 2 high:  a b c / d e f
+        let data: Vec<u8> = (0..6u8).flat_map(|v| vec![v * 10, v * 10, v * 10, 0]).collect();
+        let (a, b, c, d, e, f) = (grey(0), grey(10), grey(20), grey(30), grey(40), grey(50));
+        let cases = [
+            (Rotation::None, (3, 2), vec![a, b, c, d, e, f]),
+            (Rotation::Cw90, (2, 3), vec![c, f, b, e, a, d]),
+            (Rotation::Cw180, (3, 2), vec![f, e, d, c, b, a]),
+            (Rotation::Cw270, (2, 3), vec![d, a, e, b, f, c]),
+        ];
+        for &(rotation, size, ref expected) in cases.iter() {
+            let mut raw = RawFrame::packed(&data, PixelFormat::Bgrx8, 3, 2);
+            raw.rotation = rotation;
+            assert_eq!(raw.upright_size(), size);
+            let frame = raw.to_frame();
+            assert_eq!((frame.w, frame.h), size, "{:?}", rotation);
+            assert_eq!(&frame.pixels, expected, "{:?}", rotation);
+        }
+    }
+
+    #[test]
+    fn ten_bit_to_rgba8() {
+        // Red full, green half, blue off.
+        let v: u32 = 0x3ff << 20 | 0x200 << 10;
+        let data = [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8];
+        let raw = RawFrame::packed(&data, PixelFormat::X2Rgb10, 1, 1);
+        assert!(raw.format.is_deep());
+        assert_eq!(raw.to_rgba16(), vec![0xffff, 0x8020, 0, 0xffff]);
+        assert_eq!(raw.to_frame().pixels, vec![BGRA8 { b: 0, g: 0x80, r: 0xff, a: 0xff }]);
+    }
+
+    #[test]
+    fn sixteen_bit_passthrough() {
+        let values: [u16; 8] = [0x1234, 0xfedc, 0x0001, 0xffff, 0x00ff, 0xff00, 0x8000, 0x7fff];
+        let data: Vec<u8> = values.iter().flat_map(|&v| vec![v as u8, (v >> 8) as u8]).collect();
+        let raw = RawFrame::packed(&data, PixelFormat::Rgba16, 2, 1);
+        assert_eq!(raw.to_rgba16(), values.to_vec());
+        assert_eq!(
+            raw.to_frame().pixels,
+            vec![
+                BGRA8 { b: 0x00, g: 0xfe, r: 0x12, a: 0xff },
+                BGRA8 { b: 0x80, g: 0xff, r: 0x00, a: 0x7f },
+            ]
+        );
+    }
+}
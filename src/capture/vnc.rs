@@ -0,0 +1,450 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use des::Des;
+use des::cipher::{BlockEncrypt, KeyInit};
+use des::cipher::generic_array::GenericArray;
+use super::{BGRA8, CaptureError, CaptureSource, Frame, Rect};
+use super::pixfmt::{PixelFormat, RawFrame};
+
+const ENCODING_RAW: i32 = 0;
+const ENCODING_COPY_RECT: i32 = 1;
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+const SECURITY_NONE: u8 = 1;
+const SECURITY_VNC_AUTH: u8 = 2;
+
+// How long to wait for the rest of a message once it has started.
+const MESSAGE_TIMEOUT_SECS: u64 = 10;
+
+/*
+ * Records a machine over VNC (RFB 3.3 to 3.8). Update rectangles from the
+ * server are the change hints; only those areas are compared.
+ * The server is asked for 32 bit little endian BGRX so pixels need no
+ * conversion.
+ */
+pub struct VncCapture {
+    stream: TcpStream,
+    width: u16,
+    height: u16,
+    timeout: Duration,
+    framebuffer: Vec<BGRA8>,
+    // An incremental update request is outstanding.
+    requested: bool,
+}
+
+impl VncCapture {
+    pub fn new(address: &str, password: Option<&str>, timeout_ms: u32) -> Result<VncCapture, String> {
+        let stream = TcpStream::connect(address)
+            .map_err(|e| format!("Unable to connect to {}: {:?}", address, e))?;
+        stream.set_nodelay(true).ok();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(MESSAGE_TIMEOUT_SECS)))
+            .map_err(|e| format!("{:?}", e))?;
+
+        let mut capture = VncCapture {
+            stream: stream,
+            width: 0,
+            height: 0,
+            timeout: Duration::from_millis(timeout_ms as u64),
+            framebuffer: vec![],
+            requested: false,
+        };
+        capture.handshake(password).map_err(|e| format!("VNC handshake failed: {}", e))?;
+        capture.request_update(false).map_err(|e| format!("{:?}", e))?;
+        Ok(capture)
+    }
+
+    fn handshake(&mut self, password: Option<&str>) -> Result<(), String> {
+        let mut version = [0u8; 12];
+        self.stream.read_exact(&mut version).map_err(|e| format!("{:?}", e))?;
+        let minor = match &version[..] {
+            b"RFB 003.003\n" => 3,
+            b"RFB 003.007\n" => 7,
+            v if v.starts_with(b"RFB 003.") => 8,
+            _ => return Err(format!("Not an RFB server: {:?}", String::from_utf8_lossy(&version))),
+        };
+        self.stream
+            .write_all(format!("RFB 003.{:03}\n", minor).as_bytes())
+            .map_err(|e| format!("{:?}", e))?;
+
+        let security = if minor == 3 {
+            match self.stream.read_u32::<BigEndian>().map_err(|e| format!("{:?}", e))? {
+                0 => return Err(self.read_reason()),
+                t => t as u8,
+            }
+        } else {
+            let count = self.stream.read_u8().map_err(|e| format!("{:?}", e))?;
+            if count == 0 {
+                return Err(self.read_reason());
+            }
+            let mut types = vec![0u8; count as usize];
+            self.stream.read_exact(&mut types).map_err(|e| format!("{:?}", e))?;
+            let chosen = if password.is_some() && types.contains(&SECURITY_VNC_AUTH) {
+                SECURITY_VNC_AUTH
+            } else if types.contains(&SECURITY_NONE) {
+                SECURITY_NONE
+            } else if types.contains(&SECURITY_VNC_AUTH) {
+                return Err("Server requires a password".to_owned());
+            } else {
+                return Err(format!("No supported security type in {:?}", types));
+            };
+            self.stream.write_u8(chosen).map_err(|e| format!("{:?}", e))?;
+            chosen
+        };
+
+        match security {
+            SECURITY_NONE => (),
+            SECURITY_VNC_AUTH => {
+                let mut challenge = [0u8; 16];
+                self.stream.read_exact(&mut challenge).map_err(|e| format!("{:?}", e))?;
+                let response = vnc_auth_response(password.unwrap_or(""), challenge);
+                self.stream.write_all(&response).map_err(|e| format!("{:?}", e))?;
+            }
+            t => return Err(format!("Unsupported security type {}", t)),
+        }
+        // 3.3 and 3.7 skip the result for no authentication.
+        if minor == 8 || security != SECURITY_NONE {
+            if self.stream.read_u32::<BigEndian>().map_err(|e| format!("{:?}", e))? != 0 {
+                return Err(if minor == 8 {
+                    self.read_reason()
+                } else {
+                    "Authentication failed".to_owned()
+                });
+            }
+        }
+
+        // ClientInit: share the desktop with other viewers.
+        self.stream.write_u8(1).map_err(|e| format!("{:?}", e))?;
+
+        // ServerInit
+        self.width = self.stream.read_u16::<BigEndian>().map_err(|e| format!("{:?}", e))?;
+        self.height = self.stream.read_u16::<BigEndian>().map_err(|e| format!("{:?}", e))?;
+        let mut server_format = [0u8; 16];
+        self.stream.read_exact(&mut server_format).map_err(|e| format!("{:?}", e))?;
+        self.read_string().map_err(|e| format!("{:?}", e))?;
+        self.framebuffer = vec![BGRA8 { b: 0, g: 0, r: 0, a: 255 }; self.width as usize * self.height as usize];
+
+        // SetPixelFormat: 32bpp, depth 24, little endian, true colour, BGRX.
+        let mut message = vec![0u8, 0, 0, 0];
+        message.extend_from_slice(&[32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0]);
+        // SetEncodings
+        message.extend_from_slice(&[2, 0]);
+        let encodings = [ENCODING_COPY_RECT, ENCODING_RAW, ENCODING_DESKTOP_SIZE];
+        message.write_u16::<BigEndian>(encodings.len() as u16).unwrap();
+        for &encoding in encodings.iter() {
+            message.write_i32::<BigEndian>(encoding).unwrap();
+        }
+        self.stream.write_all(&message).map_err(|e| format!("{:?}", e))
+    }
+
+    fn read_reason(&mut self) -> String {
+        self.read_string().unwrap_or_else(|e| format!("{:?}", e))
+    }
+
+    fn read_string(&mut self) -> io::Result<String> {
+        let length = self.stream.read_u32::<BigEndian>()?;
+        let mut text = vec![0u8; length as usize];
+        self.stream.read_exact(&mut text)?;
+        Ok(String::from_utf8_lossy(&text).into_owned())
+    }
+
+    fn request_update(&mut self, incremental: bool) -> io::Result<()> {
+        let mut message = vec![3u8, incremental as u8];
+        message.write_u16::<BigEndian>(0)?;
+        message.write_u16::<BigEndian>(0)?;
+        message.write_u16::<BigEndian>(self.width)?;
+        message.write_u16::<BigEndian>(self.height)?;
+        self.stream.write_all(&message)?;
+        self.requested = true;
+        Ok(())
+    }
+
+    // Waits up to the timeout for the next server message.
+    fn next_message_type(&mut self) -> Result<Option<u8>, CaptureError> {
+        self.stream.set_read_timeout(Some(self.timeout)).ok();
+        let mut message_type = [0u8; 1];
+        let result = self.stream.read(&mut message_type);
+        self.stream
+            .set_read_timeout(Some(Duration::from_secs(MESSAGE_TIMEOUT_SECS)))
+            .ok();
+        match result {
+            Ok(0) => Err(CaptureError::Failed("VNC server closed the connection".to_owned())),
+            Ok(_) => Ok(Some(message_type[0])),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(CaptureError::Failed(format!("{:?}", e))),
+        }
+    }
+
+    // Applies a FramebufferUpdate, returning the rectangles that changed.
+    fn read_update(&mut self) -> io::Result<Vec<Rect>> {
+        let mut dirty = vec![];
+        self.stream.read_u8()?;
+        let count = self.stream.read_u16::<BigEndian>()?;
+        for _ in 0..count {
+            let x = self.stream.read_u16::<BigEndian>()? as usize;
+            let y = self.stream.read_u16::<BigEndian>()? as usize;
+            let w = self.stream.read_u16::<BigEndian>()? as usize;
+            let h = self.stream.read_u16::<BigEndian>()? as usize;
+            let encoding = self.stream.read_i32::<BigEndian>()?;
+            let rect = Rect { x: x as i32, y: y as i32, w: w as u32, h: h as u32 };
+
+            let changed = match encoding {
+                ENCODING_RAW => {
+                    self.check_rect(x, y, w, h)?;
+                    let mut data = vec![0u8; w * h * 4];
+                    self.stream.read_exact(&mut data)?;
+                    let pixels = RawFrame::packed(&data, PixelFormat::Bgrx8, w, h).to_frame().pixels;
+                    self.put_rect(x, y, w, h, &pixels)
+                }
+                ENCODING_COPY_RECT => {
+                    let src_x = self.stream.read_u16::<BigEndian>()? as usize;
+                    let src_y = self.stream.read_u16::<BigEndian>()? as usize;
+                    self.check_rect(x, y, w, h)?;
+                    self.check_rect(src_x, src_y, w, h)?;
+                    let mut pixels = Vec::with_capacity(w * h);
+                    for row in src_y..src_y + h {
+                        let start = row * self.width as usize + src_x;
+                        pixels.extend_from_slice(&self.framebuffer[start..start + w]);
+                    }
+                    self.put_rect(x, y, w, h, &pixels)
+                }
+                ENCODING_DESKTOP_SIZE => {
+                    self.width = w as u16;
+                    self.height = h as u16;
+                    self.framebuffer = vec![BGRA8 { b: 0, g: 0, r: 0, a: 255 }; w * h];
+                    // Incremental updates only cover what changed, so ask
+                    // for the whole of the new desktop.
+                    self.request_update(false)?;
+                    true
+                }
+                e => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unrequested encoding {}", e),
+                    ))
+                }
+            };
+            if changed {
+                dirty.push(rect);
+            }
+        }
+        Ok(dirty)
+    }
+
+    fn check_rect(&self, x: usize, y: usize, w: usize, h: usize) -> io::Result<()> {
+        if x + w > self.width as usize || y + h > self.height as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Rectangle {}x{} at {},{} is outside the {}x{} desktop",
+                    w,
+                    h,
+                    x,
+                    y,
+                    self.width,
+                    self.height
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn put_rect(&mut self, x: usize, y: usize, w: usize, h: usize, pixels: &[BGRA8]) -> bool {
+        let width = self.width as usize;
+        let mut changed = false;
+        for row in 0..h {
+            let start = (y + row) * width + x;
+            let dst = &mut self.framebuffer[start..start + w];
+            let src = &pixels[row * w..(row + 1) * w];
+            if dst != src {
+                dst.copy_from_slice(src);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn skip_message(&mut self, message_type: u8) -> io::Result<()> {
+        match message_type {
+            // SetColourMapEntries
+            1 => {
+                self.stream.read_u8()?;
+                self.stream.read_u16::<BigEndian>()?;
+                let count = self.stream.read_u16::<BigEndian>()?;
+                let mut entries = vec![0u8; count as usize * 6];
+                self.stream.read_exact(&mut entries)
+            }
+            // Bell
+            2 => Ok(()),
+            // ServerCutText
+            3 => {
+                let mut padding = [0u8; 3];
+                self.stream.read_exact(&mut padding)?;
+                self.read_string().map(|_| ())
+            }
+            t => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown server message {}", t),
+            )),
+        }
+    }
+}
+
+impl CaptureSource for VncCapture {
+    fn capture_frame(&mut self) -> Result<Frame, CaptureError> {
+        if !self.requested {
+            self.request_update(true).map_err(|e| CaptureError::Failed(format!("{:?}", e)))?;
+        }
+
+        loop {
+            let message_type = match self.next_message_type()? {
+                Some(t) => t,
+                None => return Err(CaptureError::Timeout),
+            };
+            if message_type != 0 {
+                self.skip_message(message_type)
+                    .map_err(|e| CaptureError::Failed(format!("{:?}", e)))?;
+                continue;
+            }
+
+            self.requested = false;
+            let dirty = self.read_update()
+                .map_err(|e| CaptureError::Failed(format!("{:?}", e)))?;
+            if dirty.is_empty() {
+                return Err(CaptureError::Timeout);
+            }
+            return Ok(Frame {
+                w: self.width as usize,
+                h: self.height as usize,
+                pixels: self.framebuffer.clone(),
+                dirty: Some(dirty),
+                deep: None,
+            });
+        }
+    }
+}
+
+// VNC authentication DES-encrypts the challenge with the password, using
+// each key byte bit-reversed.
+fn vnc_auth_response(password: &str, challenge: [u8; 16]) -> [u8; 16] {
+    let mut key = [0u8; 8];
+    for (k, p) in key.iter_mut().zip(password.bytes()) {
+        *k = p.reverse_bits();
+    }
+    let cipher = Des::new_from_slice(&key).expect("DES key is 8 bytes");
+
+    let mut response = challenge;
+    for block in response.chunks_mut(8) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use capture::{BGRA8, CaptureError, CaptureSource, Rect, grey};
+    use super::{VncCapture, ENCODING_COPY_RECT, ENCODING_DESKTOP_SIZE, ENCODING_RAW};
+
+    fn read_bytes(stream: &mut TcpStream, count: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; count];
+        stream.read_exact(&mut bytes).unwrap();
+        bytes
+    }
+
+    // FramebufferUpdateRequest: incremental flag and size.
+    fn read_request(stream: &mut TcpStream) -> (bool, u16, u16) {
+        let request = read_bytes(stream, 10);
+        assert_eq!(request[0], 3);
+        let size = |i: usize| (request[i] as u16) << 8 | request[i + 1] as u16;
+        (request[1] == 1, size(6), size(8))
+    }
+
+    fn update(rects: &[(u16, u16, u16, u16, i32, Vec<u8>)]) -> Vec<u8> {
+        let mut message = vec![0u8, 0];
+        message.write_u16::<BigEndian>(rects.len() as u16).unwrap();
+        for &(x, y, w, h, encoding, ref data) in rects {
+            for &v in &[x, y, w, h] {
+                message.write_u16::<BigEndian>(v).unwrap();
+            }
+            message.write_i32::<BigEndian>(encoding).unwrap();
+            message.extend_from_slice(data);
+        }
+        message
+    }
+
+    // A 4x2 RFB 3.8 server with no authentication.
+    fn serve(mut stream: TcpStream) {
+        stream.write_all(b"RFB 003.008\n").unwrap();
+        assert_eq!(read_bytes(&mut stream, 12), b"RFB 003.008\n");
+        stream.write_all(&[1, 1]).unwrap();
+        assert_eq!(read_bytes(&mut stream, 1), [1]);
+        stream.write_u32::<BigEndian>(0).unwrap();
+        // ClientInit, then ServerInit.
+        assert_eq!(read_bytes(&mut stream, 1), [1]);
+        let mut init = vec![0, 4, 0, 2];
+        init.extend_from_slice(&[0u8; 16]);
+        init.extend_from_slice(&[0, 0, 0, 4]);
+        init.extend_from_slice(b"test");
+        stream.write_all(&init).unwrap();
+        // SetPixelFormat, then SetEncodings with three encodings.
+        let formats = read_bytes(&mut stream, 20 + 4 + 3 * 4);
+        assert_eq!(&formats[4..8], &[32, 24, 0, 1]);
+        assert_eq!(read_request(&mut stream), (false, 4, 2));
+
+        // Raw: every pixel its own shade.
+        let raw: Vec<u8> = (0..8u8).flat_map(|v| vec![v * 10, v * 10, v * 10, 0]).collect();
+        stream.write_all(&update(&[(0, 0, 4, 2, ENCODING_RAW, raw)])).unwrap();
+
+        // CopyRect: the top left pair to the bottom right.
+        assert_eq!(read_request(&mut stream), (true, 4, 2));
+        stream.write_all(&update(&[(2, 1, 2, 1, ENCODING_COPY_RECT, vec![0, 0, 0, 0])])).unwrap();
+
+        // DesktopSize: the client must ask for the whole new desktop.
+        assert_eq!(read_request(&mut stream), (true, 4, 2));
+        stream.write_all(&update(&[(0, 0, 6, 3, ENCODING_DESKTOP_SIZE, vec![])])).unwrap();
+        assert_eq!(read_request(&mut stream), (false, 6, 3));
+
+        // A CopyRect from outside the desktop.
+        stream.write_all(&update(&[(0, 0, 2, 2, ENCODING_COPY_RECT, vec![0, 5, 0, 2])])).unwrap();
+        // Keep the connection open until the client has read everything.
+        stream.read(&mut [0u8; 1]).ok();
+    }
+
+    #[test]
+    fn handshake_and_updates() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || serve(listener.accept().unwrap().0));
+
+        let mut capture = VncCapture::new(&address, None, 5_000).unwrap();
+        let frame = capture.capture_frame().unwrap();
+        assert_eq!((frame.w, frame.h), (4, 2));
+        assert_eq!(frame.dirty, Some(vec![Rect { x: 0, y: 0, w: 4, h: 2 }]));
+        let shades: Vec<BGRA8> = (0..8u8).map(|v| grey(v * 10)).collect();
+        assert_eq!(frame.pixels, shades);
+
+        let frame = capture.capture_frame().unwrap();
+        assert_eq!(frame.dirty, Some(vec![Rect { x: 2, y: 1, w: 2, h: 1 }]));
+        assert_eq!(&frame.pixels[6..8], &shades[0..2]);
+        assert_eq!(&frame.pixels[0..6], &shades[0..6]);
+
+        let frame = capture.capture_frame().unwrap();
+        assert_eq!((frame.w, frame.h), (6, 3));
+        assert_eq!(frame.pixels, vec![grey(0); 18]);
+
+        match capture.capture_frame() {
+            Err(CaptureError::Failed(e)) => assert!(e.contains("InvalidData"), "{}", e),
+            other => panic!("Out of bounds CopyRect gave {:?}", other.map(|f| f.dirty)),
+        }
+        drop(capture);
+        server.join().unwrap();
+    }
+}
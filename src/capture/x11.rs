@@ -0,0 +1,394 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use xcb;
+use super::{BGRA8, CaptureError, CaptureSource, Cursor, Frame, Rect, WindowInfo};
+use super::pixfmt::{PixelFormat, RawFrame, Rotation};
+
+// Rectangles listed per frame before they are merged into one.
+const MAX_DIRTY_RECTS: usize = 32;
+
+/*
+ * Captures the root window of an X display (works under Xvfb too).
+ * X11 has no "wait for a new frame" call, so an unchanged screen waits out
+ * the timeout and reports CaptureError::Timeout like DXGI does.
+ * GetImage never includes the pointer; XFixes reports it separately.
+ * With DAMAGE, an idle screen costs no GetImage calls at all and only the
+ * damaged rectangles are fetched and compared.
+ * 30-bit displays are read as 10 bits per channel; with keep_deep the full
+ * depth is kept for 16-bit PNGs (whole frames only, so DAMAGE is not used).
+ */
+pub struct X11Capture {
+    conn: xcb::Connection,
+    root: xcb::Window,
+    width: u16,
+    height: u16,
+    format: PixelFormat,
+    keep_deep: bool,
+    timeout: Duration,
+    last: Option<Vec<BGRA8>>,
+    net_wm_name: xcb::Atom,
+    utf8_string: xcb::Atom,
+    has_xfixes: bool,
+    damage: Option<DamageState>,
+}
+
+#[derive(Clone, Copy)]
+struct DamageState {
+    damage: u32,
+    region: u32,
+    notify_event: u8,
+}
+
+impl X11Capture {
+    pub fn new(
+        display: Option<&str>,
+        timeout_ms: u32,
+        use_damage: bool,
+        keep_deep: bool,
+    ) -> Result<X11Capture, String> {
+        let (conn, screen_num) = xcb::Connection::connect(display)
+            .map_err(|e| format!("Unable to connect to X server: {:?}", e))?;
+        let (root, width, height, format) = {
+            let setup = conn.get_setup();
+            let screen = match setup.roots().nth(screen_num as usize) {
+                Some(s) => s,
+                None => return Err(format!("No X screen {}", screen_num)),
+            };
+            let bits_per_pixel = setup
+                .pixmap_formats()
+                .find(|f| f.depth() == screen.root_depth())
+                .map(|f| f.bits_per_pixel())
+                .unwrap_or(0);
+            if setup.image_byte_order() != xcb::IMAGE_ORDER_LSB_FIRST as u8 {
+                return Err("Big endian X servers aren't supported".to_owned());
+            }
+            let format = match (screen.root_depth(), bits_per_pixel) {
+                (24, 32) | (32, 32) => PixelFormat::Bgrx8,
+                (30, 32) => PixelFormat::X2Rgb10,
+                (16, 16) => PixelFormat::Rgb565,
+                (depth, bpp) => {
+                    return Err(format!("Unsupported X screen depth {} ({} bits per pixel)", depth, bpp))
+                }
+            };
+            (screen.root(), screen.width_in_pixels(), screen.height_in_pixels(), format)
+        };
+        let keep_deep = keep_deep && format.is_deep();
+        let use_damage = use_damage && !keep_deep;
+        let net_wm_name = intern_atom(&conn, "_NET_WM_NAME");
+        let utf8_string = intern_atom(&conn, "UTF8_STRING");
+        let has_xfixes = xcb::xfixes::query_version(&conn, 4, 0).get_reply().is_ok();
+        let damage = if use_damage && has_xfixes {
+            setup_damage(&conn, root)
+        } else {
+            None
+        };
+        if use_damage && damage.is_none() {
+            eprintln!("DAMAGE not available, comparing whole frames");
+        }
+
+        Ok(X11Capture {
+            conn: conn,
+            root: root,
+            width: width,
+            height: height,
+            format: format,
+            keep_deep: keep_deep,
+            timeout: Duration::from_millis(timeout_ms as u64),
+            last: None,
+            net_wm_name: net_wm_name,
+            utf8_string: utf8_string,
+            has_xfixes: has_xfixes,
+            damage: damage,
+        })
+    }
+
+    fn grab(&self) -> Result<Frame, CaptureError> {
+        let (width, height) = (self.width, self.height);
+        self.grab_rect(0, 0, width, height, self.keep_deep)
+    }
+
+    fn grab_rect(&self, x: i16, y: i16, width: u16, height: u16, deep: bool) -> Result<Frame, CaptureError> {
+        let reply = xcb::get_image(
+            &self.conn,
+            xcb::IMAGE_FORMAT_Z_PIXMAP as u8,
+            self.root,
+            x,
+            y,
+            width,
+            height,
+            !0,
+        ).get_reply()
+            .map_err(|e| CaptureError::Failed(format!("GetImage failed: {:?}", e)))?;
+
+        // Rows are padded to the server's scanline unit.
+        let data = reply.data();
+        let raw = RawFrame {
+            data: data,
+            format: self.format,
+            width: width as usize,
+            height: height as usize,
+            stride: if height > 0 { data.len() / height as usize } else { 0 },
+            rotation: Rotation::None,
+        };
+        raw.check().map_err(CaptureError::Failed)?;
+        let mut frame = raw.to_frame();
+        if deep {
+            frame.deep = Some(raw.to_rgba16());
+        }
+        Ok(frame)
+    }
+
+    fn wait_for_damage(&self, notify_event: u8) -> bool {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let mut damaged = false;
+            while let Some(event) = self.conn.poll_for_event() {
+                if event.response_type() & !0x80 == notify_event {
+                    damaged = true;
+                }
+            }
+            if damaged {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Refreshes the damaged parts of `last`. Only the damaged rectangles are
+    // compared, and each is cut down to the box around the pixels that
+    // really changed; undamaged areas are never read.
+    fn capture_damaged(&mut self, state: DamageState) -> Result<Frame, CaptureError> {
+        if !self.wait_for_damage(state.notify_event) {
+            return Err(CaptureError::Timeout);
+        }
+        xcb::damage::subtract(&self.conn, state.damage, xcb::NONE, state.region);
+        let reply = xcb::xfixes::fetch_region(&self.conn, state.region)
+            .get_reply()
+            .map_err(|e| CaptureError::Failed(format!("FetchRegion failed: {:?}", e)))?;
+
+        let width = self.width as usize;
+        let mut dirty = vec![];
+        for r in reply.rectangles() {
+            if r.width() == 0 || r.height() == 0 {
+                continue;
+            }
+            let fetched = self.grab_rect(r.x(), r.y(), r.width(), r.height(), false)?.pixels;
+            let last = match self.last.as_mut() {
+                Some(last) => last,
+                None => return Err(CaptureError::Failed("No frame to update".to_owned())),
+            };
+            let rect = Rect {
+                x: r.x() as i32,
+                y: r.y() as i32,
+                w: r.width() as u32,
+                h: r.height() as u32,
+            };
+            if let Some(changed) = update_rect(last, width, rect, &fetched) {
+                dirty.push(changed);
+            }
+        }
+
+        if dirty.is_empty() {
+            return Err(CaptureError::Timeout);
+        }
+        super::limit_rects(&mut dirty, MAX_DIRTY_RECTS);
+        Ok(Frame {
+            w: width,
+            h: self.height as usize,
+            pixels: self.last.clone().unwrap_or_default(),
+            dirty: Some(dirty),
+            deep: None,
+        })
+    }
+
+    fn property(&self, window: xcb::Window, atom: xcb::Atom, atom_type: xcb::Atom) -> Vec<u8> {
+        match xcb::get_property(&self.conn, false, window, atom, atom_type, 0, 1024).get_reply() {
+            Ok(reply) => reply.value::<u8>().to_vec(),
+            Err(_) => vec![],
+        }
+    }
+
+    // Reparenting window managers put the title on a child of the frame.
+    fn names(&self, window: xcb::Window, depth: u32) -> (String, String) {
+        let mut title = self.property(window, self.net_wm_name, self.utf8_string);
+        if title.is_empty() {
+            title = self.property(window, xcb::ATOM_WM_NAME, xcb::ATOM_STRING);
+        }
+        let class = self.property(window, xcb::ATOM_WM_CLASS, xcb::ATOM_STRING);
+
+        if title.is_empty() && class.is_empty() && depth > 0 {
+            if let Ok(tree) = xcb::query_tree(&self.conn, window).get_reply() {
+                for &child in tree.children() {
+                    let names = self.names(child, depth - 1);
+                    if !names.0.is_empty() || !names.1.is_empty() {
+                        return names;
+                    }
+                }
+            }
+        }
+
+        // WM_CLASS is "instance\0class\0"; match against both.
+        let class = class
+            .split(|&c| c == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect::<Vec<String>>()
+            .join(" ");
+        (String::from_utf8_lossy(&title).into_owned(), class)
+    }
+
+    fn window_info(&self, window: xcb::Window) -> Option<WindowInfo> {
+        let attributes = xcb::get_window_attributes(&self.conn, window).get_reply().ok()?;
+        if attributes.map_state() != xcb::MAP_STATE_VIEWABLE as u8 {
+            return None;
+        }
+        let geometry = xcb::get_geometry(&self.conn, window).get_reply().ok()?;
+        let origin = xcb::translate_coordinates(&self.conn, window, self.root, 0, 0)
+            .get_reply()
+            .ok()?;
+        let border = geometry.border_width() as u32 * 2;
+        let (title, class) = self.names(window, 2);
+
+        Some(WindowInfo {
+            title: title,
+            class: class,
+            x: origin.dst_x() as i32 - geometry.border_width() as i32,
+            y: origin.dst_y() as i32 - geometry.border_width() as i32,
+            w: geometry.width() as u32 + border,
+            h: geometry.height() as u32 + border,
+        })
+    }
+}
+
+impl CaptureSource for X11Capture {
+    fn capture_frame(&mut self) -> Result<Frame, CaptureError> {
+        if let (Some(state), true) = (self.damage, self.last.is_some()) {
+            return self.capture_damaged(state);
+        }
+
+        let frame = self.grab()?;
+        if self.last.as_ref() == Some(&frame.pixels) {
+            thread::sleep(self.timeout);
+            return Err(CaptureError::Timeout);
+        }
+        self.last = Some(frame.pixels.clone());
+        Ok(frame)
+    }
+
+    fn windows(&mut self) -> Vec<WindowInfo> {
+        let children = match xcb::query_tree(&self.conn, self.root).get_reply() {
+            Ok(tree) => tree.children().to_vec(),
+            Err(_) => return vec![],
+        };
+        children.iter().filter_map(|&w| self.window_info(w)).collect()
+    }
+
+    fn cursor(&mut self) -> Option<Cursor> {
+        if !self.has_xfixes {
+            return None;
+        }
+        let reply = xcb::xfixes::get_cursor_image(&self.conn).get_reply().ok()?;
+        Some(Cursor {
+            x: reply.x() as i32,
+            y: reply.y() as i32,
+            xhot: reply.xhot() as u32,
+            yhot: reply.yhot() as u32,
+            w: reply.width() as u32,
+            h: reply.height() as u32,
+            pixels: reply
+                .cursor_image()
+                .iter()
+                .map(|&p| {
+                    BGRA8 {
+                        b: p as u8,
+                        g: (p >> 8) as u8,
+                        r: (p >> 16) as u8,
+                        a: (p >> 24) as u8,
+                    }
+                })
+                .collect(),
+        })
+    }
+}
+
+// Copies `fetched` (the pixels of `rect`) into `last`, returning the part of
+// `rect` that differed, if any.
+fn update_rect(last: &mut [BGRA8], width: usize, rect: Rect, fetched: &[BGRA8]) -> Option<Rect> {
+    let (rx, ry, rw) = (rect.x as usize, rect.y as usize, rect.w as usize);
+    let (mut left, mut right, mut top, mut bottom) = (rw, 0, None, 0);
+    for row in 0..rect.h as usize {
+        let start = (ry + row) * width + rx;
+        let src = &fetched[row * rw..(row + 1) * rw];
+        let dst = &mut last[start..start + rw];
+        let first = match (0..rw).find(|&i| dst[i] != src[i]) {
+            Some(first) => first,
+            None => continue,
+        };
+        let end = (first..rw).rev().find(|&i| dst[i] != src[i]).unwrap_or(first) + 1;
+        dst[first..end].copy_from_slice(&src[first..end]);
+        left = left.min(first);
+        right = right.max(end);
+        top = top.or(Some(row));
+        bottom = row + 1;
+    }
+    top.map(|top| {
+        Rect {
+            x: (rx + left) as i32,
+            y: (ry + top) as i32,
+            w: (right - left) as u32,
+            h: (bottom - top) as u32,
+        }
+    })
+}
+
+fn setup_damage(conn: &xcb::Connection, root: xcb::Window) -> Option<DamageState> {
+    xcb::damage::query_version(conn, 1, 1).get_reply().ok()?;
+    let first_event = conn.get_extension_data(xcb::damage::id())?.first_event();
+
+    let damage = conn.generate_id();
+    xcb::damage::create(conn, damage, root, xcb::damage::REPORT_LEVEL_NON_EMPTY as u8);
+    let region = conn.generate_id();
+    xcb::xfixes::create_region(conn, region, &[]);
+    conn.flush();
+
+    Some(DamageState {
+        damage: damage,
+        region: region,
+        notify_event: first_event + xcb::damage::NOTIFY,
+    })
+}
+
+fn intern_atom(conn: &xcb::Connection, name: &str) -> xcb::Atom {
+    xcb::intern_atom(conn, false, name)
+        .get_reply()
+        .map(|r| r.atom())
+        .unwrap_or(xcb::ATOM_NONE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::update_rect;
+    use capture::{Rect, grey};
+
+    #[test]
+    fn update_rect_shrinks_to_changes() {
+        let (w, h) = (8, 6);
+        let mut last = vec![grey(0); w * h];
+        let damaged = Rect { x: 1, y: 1, w: 5, h: 4 };
+        let mut fetched = vec![grey(0); 5 * 4];
+        fetched[1 * 5 + 2] = grey(9);
+        fetched[2 * 5 + 3] = grey(9);
+
+        let changed = update_rect(&mut last, w, damaged, &fetched);
+        assert_eq!(changed, Some(Rect { x: 3, y: 2, w: 2, h: 2 }));
+        assert_eq!(last[2 * w + 3], grey(9));
+        assert_eq!(last[3 * w + 4], grey(9));
+        assert_eq!(last.iter().filter(|&&p| p != grey(0)).count(), 2);
+
+        // Damaged but redrawn the same.
+        assert_eq!(update_rect(&mut last, w, damaged, &fetched), None);
+    }
+}
@@ -0,0 +1,720 @@
+/*
+ * Slide differences as written by pngdiff. Each image in a rewritten
+ * timings.json holds the pixels that changed since the entry before it,
+ * opaque, with unchanged pixels fully transparent. JPEGs and the first
+ * image have no transparent pixels, so they replace the frame. (Older
+ * sessions used black for unchanged pixels with a black tRNS, which
+ * decodes the same way.)
+ * Entries tagged keyframe=1 are whole frames, so reading can start there.
+ * Where the resolution changes the entry is a keyframe tagged size=WxH.
+ * A delta applies over the entry before it, or the one given by ref=N
+ * (position in timings.json) when pngdiff found a better reference.
+ * Deltas where little changed can instead be stored as patches, cropped
+ * to the changed areas: patches=path@x,y,w,h;... (the first path is also
+ * the entry's image).
+ * Images are decoded by content, as pngdiff can write PNG data over a
+ * file it earlier named .jpg.
+ */
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use image;
+use image::{DynamicImage, GenericImage, ImageBuffer, Rgb, RgbImage, RgbaImage};
+use image::DynamicImage::{ImageRgb8, ImageRgba8};
+use rayon::prelude::*;
+use serde_json;
+use capture::{limit_rects, Rect};
+use manifest;
+
+// Unchanged gaps narrower than this don't split a changed area in two.
+const PATCH_GAP: u32 = 16;
+const MAX_PATCHES: usize = 8;
+// Tags that describe how an entry is stored, not the frame.
+pub const DELTA_TAGS: [&'static str; 4] = ["ref", "keyframe", "patches", "size"];
+
+pub fn calc_percent_transparent(transparent: u64, total: u64) -> u64 {
+    if total == 0 {
+        return 0;
+    }
+
+    let raw_percent = (transparent * 100) / total;
+
+    match (transparent, raw_percent) {
+        (0, 0) => 0,
+        (_, 0) => 1,
+        (_, n) => n,
+    }
+}
+
+// Both images must be the same size.
+pub fn diff2(imga: &DynamicImage, imgb: &DynamicImage) -> (Arc<DynamicImage>, u64) {
+    diff_within(imga, imgb, 0)
+}
+
+// As diff2, but pixels within `tolerance` (per channel) of `imga` count as
+// unchanged.
+pub fn diff_within(imga: &DynamicImage, imgb: &DynamicImage, tolerance: u8) -> (Arc<DynamicImage>, u64) {
+    let (w, h) = imga.dimensions();
+    let mut imgc = RgbaImage::new(w, h);
+
+    // RGB frames (the usual case) are compared as they are; anything else as
+    // RGBA, which is what get_pixel would give.
+    let pixels_same = match (imga, imgb) {
+        (&ImageRgb8(ref a), &ImageRgb8(ref b)) => diff_rows(a, b, 3, w, tolerance, &mut imgc),
+        _ => diff_rows(&imga.to_rgba(), &imgb.to_rgba(), 4, w, tolerance, &mut imgc),
+    };
+
+    (
+        Arc::new(ImageRgba8(imgc)),
+        calc_percent_transparent(pixels_same, w as u64 * h as u64),
+    )
+}
+
+// Writes the delta of `b` over `a` (both `channels` bytes a pixel) into
+// `out` a row at a time, in parallel. Returns how many pixels are the same.
+fn diff_rows(a: &[u8], b: &[u8], channels: usize, w: u32, tolerance: u8, out: &mut [u8]) -> u64 {
+    if w == 0 {
+        return 0;
+    }
+    let row = w as usize * channels;
+    out.par_chunks_mut(w as usize * 4)
+        .zip(a.par_chunks(row))
+        .zip(b.par_chunks(row))
+        .map(|((out_row, a_row), b_row)| {
+            let mut pixels_same = 0;
+            let pixels = a_row.chunks(channels).zip(b_row.chunks(channels));
+            for (out_pixel, (pixel_a, pixel_b)) in out_row.chunks_mut(4).zip(pixels) {
+                let same = match tolerance {
+                    0 => pixel_a == pixel_b,
+                    _ => pixel_a.iter().zip(pixel_b).all(|(&x, &y)| error(x, y) <= tolerance),
+                };
+                if same {
+                    // Already transparent black.
+                    pixels_same += 1;
+                } else {
+                    out_pixel[..3].copy_from_slice(&pixel_b[..3]);
+                    out_pixel[3] = 255;
+                }
+            }
+            pixels_same
+        })
+        .sum()
+}
+
+fn error(a: u8, b: u8) -> u8 {
+    if a > b { a - b } else { b - a }
+}
+
+// Largest difference in any channel between two frames of the same size.
+pub fn max_error(a: &RgbImage, b: &RgbImage) -> u8 {
+    a.iter().zip(b.iter()).map(|(&x, &y)| error(x, y)).max().unwrap_or(0)
+}
+
+// Merges two deltas of the same frame: pixels from `image_base` where it has
+// them, otherwise from `image_extra`. Both must be the same size.
+pub fn add2(image_base: DynamicImage, image_extra: &DynamicImage) -> (DynamicImage, u64) {
+    let (w, h) = image_base.dimensions();
+    let mut image_output = match image_base {
+        ImageRgba8(rgba) => rgba,
+        other => other.to_rgba(),
+    };
+    let extra = match *image_extra {
+        ImageRgba8(ref rgba) => Cow::Borrowed(rgba),
+        ref other => Cow::Owned(other.to_rgba()),
+    };
+
+    let mut pixels_transparent: u64 = 0;
+    if w > 0 {
+        let row = w as usize * 4;
+        pixels_transparent = image_output
+            .par_chunks_mut(row)
+            .zip(extra.par_chunks(row))
+            .map(|(out_row, extra_row)| {
+                let mut transparent = 0;
+                for (pixel_a, pixel_b) in out_row.chunks_mut(4).zip(extra_row.chunks(4)) {
+                    if pixel_a[3] == 0 {
+                        pixel_a.copy_from_slice(pixel_b);
+                        if pixel_b[3] == 0 {
+                            transparent += 1;
+                        }
+                    }
+                }
+                transparent
+            })
+            .sum();
+    }
+
+    (
+        ImageRgba8(image_output),
+        calc_percent_transparent(pixels_transparent, w as u64 * h as u64),
+    )
+}
+
+// A whole frame as a delta: every pixel opaque.
+pub fn keyframe(image: &DynamicImage) -> DynamicImage {
+    let mut rgba = image.to_rgba();
+    for pixel in rgba.pixels_mut() {
+        pixel[3] = 255;
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+// The frame after `base` given its delta. With no base (the first entry)
+// the delta is drawn over black.
+pub fn apply_delta(base: Option<&RgbImage>, delta: &DynamicImage) -> Result<RgbImage, String> {
+    let (w, h) = delta.dimensions();
+    let mut frame = match base {
+        Some(base) if base.dimensions() == (w, h) => base.clone(),
+        Some(base) => {
+            return Err(format!(
+                "Delta is {}x{} but the frame before it is {}x{}",
+                w,
+                h,
+                base.width(),
+                base.height()
+            ))
+        }
+        None => RgbImage::new(w, h),
+    };
+
+    let delta = delta.to_rgba();
+    for (x, y, pixel) in delta.enumerate_pixels() {
+        if pixel[3] != 0 {
+            frame.put_pixel(x, y, Rgb([pixel[0], pixel[1], pixel[2]]));
+        }
+    }
+    Ok(frame)
+}
+
+// Draws a patch's changed pixels onto a frame at (x, y).
+pub fn apply_patch(frame: &mut RgbImage, patch: &DynamicImage, x: u32, y: u32) -> Result<(), String> {
+    let (w, h) = patch.dimensions();
+    if x + w > frame.width() || y + h > frame.height() {
+        return Err(format!(
+            "Patch {}x{} at {},{} is outside the {}x{} frame",
+            w,
+            h,
+            x,
+            y,
+            frame.width(),
+            frame.height()
+        ));
+    }
+    let patch = patch.to_rgba();
+    for (px, py, pixel) in patch.enumerate_pixels() {
+        if pixel[3] != 0 {
+            frame.put_pixel(x + px, y + py, Rgb([pixel[0], pixel[1], pixel[2]]));
+        }
+    }
+    Ok(())
+}
+
+// Areas of a delta with changed (opaque) pixels.
+pub fn changed_rects(delta: &RgbaImage) -> Vec<Rect> {
+    let (w, h) = delta.dimensions();
+    let changed = |x: u32, y: u32| delta.get_pixel(x, y)[3] != 0;
+
+    let mut rects = vec![];
+    for (top, bottom) in spans(h, |y| (0..w).any(|x| changed(x, y))) {
+        for (left, right) in spans(w, |x| (top..bottom).any(|y| changed(x, y))) {
+            // Rows of the band this column range actually uses.
+            let rows = spans(bottom - top, |dy| (left..right).any(|x| changed(x, top + dy)));
+            let first = rows.first().map_or(0, |r| r.0);
+            let last = rows.last().map_or(0, |r| r.1);
+            rects.push(Rect {
+                x: left as i32,
+                y: (top + first) as i32,
+                w: right - left,
+                h: last - first,
+            });
+        }
+    }
+    limit_rects(&mut rects, MAX_PATCHES);
+    rects
+}
+
+// [start, end) runs of 0..len where `changed` holds, joined across gaps
+// narrower than PATCH_GAP.
+fn spans<F: Fn(u32) -> bool>(len: u32, changed: F) -> Vec<(u32, u32)> {
+    let mut spans: Vec<(u32, u32)> = vec![];
+    for i in 0..len {
+        if !changed(i) {
+            continue;
+        }
+        let extend = spans.last().map_or(false, |&(_, end)| i - end < PATCH_GAP);
+        if extend {
+            if let Some(span) = spans.last_mut() {
+                span.1 = i + 1;
+            }
+        } else {
+            spans.push((i, i + 1));
+        }
+    }
+    spans
+}
+
+pub fn crop(image: &RgbaImage, rect: &Rect) -> RgbaImage {
+    ImageBuffer::from_fn(rect.w, rect.h, |x, y| {
+        *image.get_pixel(rect.x as u32 + x, rect.y as u32 + y)
+    })
+}
+
+// Which entries of a session to rebuild.
+pub enum Selection {
+    All,
+    // Position in timings.json, from 0.
+    Index(usize),
+    // Entries timed between these (in seconds), inclusive.
+    Range(f64, f64),
+}
+
+impl Selection {
+    fn contains(&self, index: usize, time: f64) -> bool {
+        match *self {
+            Selection::All => true,
+            Selection::Index(n) => index == n,
+            Selection::Range(from, to) => time >= from && time <= to,
+        }
+    }
+
+    // Nothing later can be selected.
+    fn done(&self, index: usize, time: f64) -> bool {
+        match *self {
+            Selection::All => false,
+            Selection::Index(n) => index >= n,
+            Selection::Range(_, to) => time > to,
+        }
+    }
+}
+
+pub fn load_image(path: &Path) -> Result<DynamicImage, String> {
+    let mut data = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|e| format!("Error reading {:?}: {:?}", path, e))?;
+    image::load_from_memory(&data).map_err(|e| format!("Error decoding {:?}: {:?}", path, e))
+}
+
+// The entry a delta applies over: its ref tag, or else the entry before.
+// Keyframes (and the first entry) have none.
+fn reference_of(timings: &[Vec<String>], index: usize) -> Result<Option<usize>, String> {
+    let entry = &timings[index];
+    if index == 0 || manifest::entry_tag(entry, "keyframe").is_some() {
+        return Ok(None);
+    }
+    match manifest::entry_tag(entry, "ref") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(reference) if reference < index => Ok(Some(reference)),
+            _ => Err(format!("Entry {} has a bad reference: {}", index, value)),
+        },
+        None => Ok(Some(index - 1)),
+    }
+}
+
+// What reconstruct managed: how many frames were emitted, and the selected
+// entries that couldn't be rebuilt, with why.
+pub struct Rebuilt {
+    pub emitted: usize,
+    pub failed: Vec<(usize, String)>,
+}
+
+// The frame of one entry, given the frame its delta applies over.
+fn decode_entry(dir: &Path, index: usize, entry: &[String], base: Option<&RgbImage>) -> Result<RgbImage, String> {
+    let patches = manifest::entry_tag(entry, "patches")
+        .map(|p| manifest::parse_patches(p).ok_or_else(|| format!("Entry {} has bad patches: {}", index, p)));
+    match (patches, base) {
+        (Some(patches), Some(base)) => {
+            let mut frame = base.clone();
+            for (path, rect) in patches? {
+                let patch = load_image(&dir.join(&path))?;
+                apply_patch(&mut frame, &patch, rect.x as u32, rect.y as u32)
+                    .map_err(|e| format!("Entry {}: {}", index, e))?;
+            }
+            Ok(frame)
+        }
+        (Some(_), None) => Err(format!("Entry {} has patches but nothing to apply them to", index)),
+        (None, base) => {
+            let delta = load_image(&dir.join(&entry[1]))?;
+            apply_delta(base, &delta).map_err(|e| format!("Entry {}: {}", index, e))
+        }
+    }
+}
+
+// Applies the deltas of a rewritten session, calling `emit` in order with
+// each selected entry and its full frame. Only the entries the selection
+// depends on are decoded (back to the nearest keyframes), and frames are
+// dropped once nothing later refers to them. An entry that can't be
+// rebuilt (a missing or corrupt file) fails the entries that depend on it,
+// and rebuilding carries on from the next keyframe. Errors in timings.json
+// itself, or from `emit`, stop it.
+pub fn reconstruct<F>(
+    dir: &Path,
+    timings: &[Vec<String>],
+    selection: &Selection,
+    mut emit: F,
+) -> Result<Rebuilt, String>
+where
+    F: FnMut(usize, &[String], &RgbImage) -> Result<(), String>,
+{
+    let mut selected = vec![false; timings.len()];
+    let mut needed = vec![false; timings.len()];
+    for (index, entry) in timings.iter().enumerate() {
+        if entry.len() < 2 {
+            return Err(format!("Entry {} length wrong: {:?}", index, entry));
+        }
+        let time = manifest::parse_frametime(&entry[0])
+            .ok_or_else(|| format!("Entry {} has a bad time: {}", index, entry[0]))?;
+        if !selection.contains(index, time) {
+            if selection.done(index, time) {
+                break;
+            }
+            continue;
+        }
+        selected[index] = true;
+        let mut dependency = Some(index);
+        while let Some(i) = dependency {
+            if needed[i] {
+                break;
+            }
+            needed[i] = true;
+            dependency = reference_of(timings, i)?;
+        }
+    }
+
+    // Last entry each frame is needed for.
+    let mut last_use: Vec<usize> = (0..timings.len()).collect();
+    for index in 0..timings.len() {
+        if needed[index] {
+            if let Some(reference) = reference_of(timings, index)? {
+                last_use[reference] = last_use[reference].max(index);
+            }
+        }
+    }
+
+    // Frames that couldn't be rebuilt are never added.
+    let mut frames: HashMap<usize, RgbImage> = HashMap::new();
+    let mut rebuilt = Rebuilt {
+        emitted: 0,
+        failed: vec![],
+    };
+    for index in (0..timings.len()).filter(|&i| needed[i]) {
+        let entry = &timings[index];
+        let frame = match reference_of(timings, index)? {
+            Some(reference) => match frames.get(&reference) {
+                Some(base) => decode_entry(dir, index, entry, Some(base)),
+                None => Err(format!("Entry {} applies over entry {}, which couldn't be rebuilt", index, reference)),
+            },
+            None => decode_entry(dir, index, entry, None),
+        };
+        match frame {
+            Ok(frame) => {
+                if selected[index] {
+                    emit(index, entry, &frame)?;
+                    rebuilt.emitted += 1;
+                }
+                if last_use[index] > index {
+                    frames.insert(index, frame);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                if selected[index] {
+                    rebuilt.failed.push((index, e));
+                }
+            }
+        }
+        frames.retain(|&i, _| last_use[i] > index);
+    }
+    Ok(rebuilt)
+}
+
+// Rebuilds the selected entries into `output` as frameNNN.png, with a
+// timings.json of their own that drops the delta tags, so the output is an
+// ordinary session again (without any entries that couldn't be rebuilt).
+pub fn undiff(dir: &Path, timings: &[Vec<String>], selection: &Selection, output: &Path) -> Result<Rebuilt, String> {
+    let mut timings_new: Vec<Vec<String>> = vec![];
+    let rebuilt = reconstruct(dir, timings, selection, |index, entry, frame| {
+        let name = format!("frame{:03}.png", index);
+        frame
+            .save(output.join(&name))
+            .map_err(|e| format!("Error saving {}: {:?}", name, e))?;
+        println!("Rebuilt entry {} @ {} as `{}`", index, entry[0], name);
+        let mut entry_new = entry.to_vec();
+        entry_new[1] = name;
+        for tag in DELTA_TAGS.iter() {
+            manifest::remove_entry_tag(&mut entry_new, tag);
+        }
+        timings_new.push(entry_new);
+        Ok(())
+    })?;
+
+    File::create(output.join("timings.json"))
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|f| serde_json::to_writer(f, &timings_new).map_err(|e| format!("{:?}", e)))
+        .map_err(|e| format!("Error writing timings file: {}", e))?;
+    Ok(rebuilt)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::Instant;
+    use image::{DynamicImage, GenericImage, Pixel, Rgb, RgbImage, Rgba};
+    use image::DynamicImage::{ImageRgb8, ImageRgba8};
+    use capture::Rect;
+    use manifest;
+    use super::{add2, apply_patch, calc_percent_transparent, changed_rects, crop, diff2, keyframe, reconstruct,
+                Selection};
+
+    // The original pixel at a time versions that diff2 and add2 must match.
+    fn diff2_pixelwise(imga: &DynamicImage, imgb: &DynamicImage) -> (DynamicImage, u64) {
+        let (w, h) = imga.dimensions();
+        let mut imgc = DynamicImage::new_rgba8(w, h);
+        let mut pixels_same: u64 = 0;
+        for y in 0..h {
+            for x in 0..w {
+                let pixel_a = imga.get_pixel(x, y);
+                let pixel_b = imgb.get_pixel(x, y);
+                if pixel_a == pixel_b {
+                    imgc.put_pixel(x, y, Rgba::from_channels(0, 0, 0, 0));
+                    pixels_same += 1;
+                } else {
+                    imgc.put_pixel(x, y, Rgba::from_channels(pixel_b[0], pixel_b[1], pixel_b[2], 255));
+                }
+            }
+        }
+        (imgc, calc_percent_transparent(pixels_same, w as u64 * h as u64))
+    }
+
+    fn add2_pixelwise(image_base: DynamicImage, image_extra: &DynamicImage) -> (DynamicImage, u64) {
+        let (w, h) = image_base.dimensions();
+        let mut image_output = DynamicImage::new_rgba8(w, h);
+        let mut pixels_transparent: u64 = 0;
+        for y in 0..h {
+            for x in 0..w {
+                let pixel_a = image_base.get_pixel(x, y);
+                if pixel_a[3] == 0 {
+                    let pixel_b = image_extra.get_pixel(x, y);
+                    image_output.put_pixel(x, y, pixel_b);
+                    if pixel_b[3] == 0 {
+                        pixels_transparent += 1;
+                    }
+                } else {
+                    image_output.put_pixel(x, y, pixel_a);
+                }
+            }
+        }
+        (image_output, calc_percent_transparent(pixels_transparent, w as u64 * h as u64))
+    }
+
+    // xorshift, so the frames are the same every run.
+    fn noise(seed: &mut u32) -> u32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed
+    }
+
+    // A frame, then the same frame with about a third of the pixels changed
+    // (some of them to true black, some only in alpha).
+    fn frames(w: u32, h: u32, rgba: bool, seed: u32) -> (DynamicImage, DynamicImage) {
+        let mut seed = seed;
+        let mut a = DynamicImage::new_rgba8(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let v = noise(&mut seed);
+                let alpha = if rgba { (v >> 24) as u8 | 1 } else { 255 };
+                a.put_pixel(x, y, Rgba::from_channels(v as u8, (v >> 8) as u8, (v >> 16) as u8, alpha));
+            }
+        }
+        let mut b = a.clone();
+        for y in 0..h {
+            for x in 0..w {
+                let mut pixel = a.get_pixel(x, y);
+                match noise(&mut seed) % 9 {
+                    0 => pixel = Rgba::from_channels(0, 0, 0, 255),
+                    1 => pixel[1] = pixel[1].wrapping_add(1),
+                    2 if rgba => pixel[3] = pixel[3].wrapping_add(7),
+                    _ => continue,
+                }
+                b.put_pixel(x, y, pixel);
+            }
+        }
+        if rgba {
+            (a, b)
+        } else {
+            (DynamicImage::ImageRgb8(a.to_rgb()), DynamicImage::ImageRgb8(b.to_rgb()))
+        }
+    }
+
+    const SIZES: [(u32, u32); 6] = [(17, 9), (33, 1), (1, 33), (1, 1), (64, 3), (5, 40)];
+
+    fn assert_same(name: &str, old: &(DynamicImage, u64), new: (&DynamicImage, u64)) {
+        assert_eq!(old.1, new.1, "{} percent", name);
+        assert_eq!(old.0.dimensions(), new.0.dimensions(), "{} size", name);
+        assert!(old.0.raw_pixels() == new.0.to_rgba().into_raw(), "{} pixels", name);
+    }
+
+    #[test]
+    fn diff2_matches_pixelwise() {
+        for (n, &(w, h)) in SIZES.iter().enumerate() {
+            for &rgba in &[false, true] {
+                let (a, b) = frames(w, h, rgba, n as u32 + 1);
+                let name = format!("{}x{} rgba={}", w, h, rgba);
+                for &(x, y) in &[(&a, &b), (&b, &a), (&a, &a)] {
+                    let new = diff2(x, y);
+                    assert_same(&name, &diff2_pixelwise(x, y), (&new.0, new.1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn true_black_stays_opaque() {
+        let (a, b) = frames(17, 9, false, 3);
+        let (delta, _) = diff2(&a, &b);
+        let delta = delta.to_rgba();
+        let mut blacks = 0;
+        for (x, y, pixel) in b.pixels() {
+            if pixel == Rgba::from_channels(0, 0, 0, 255) && a.get_pixel(x, y) != pixel {
+                assert_eq!(delta.get_pixel(x, y).data, [0, 0, 0, 255]);
+                blacks += 1;
+            }
+        }
+        assert!(blacks > 0);
+    }
+
+    #[test]
+    fn add2_matches_pixelwise() {
+        for (n, &(w, h)) in SIZES.iter().enumerate() {
+            for &rgba in &[false, true] {
+                let (a, b) = frames(w, h, rgba, n as u32 + 11);
+                let name = format!("{}x{} rgba={}", w, h, rgba);
+                let forward = diff2(&a, &b).0;
+                let backward = diff2(&b, &a).0;
+                // Deltas both ways round, and whole frames as either side.
+                let cases = [
+                    (&*forward, &*backward),
+                    (&*backward, &*forward),
+                    (&a, &*forward),
+                    (&*forward, &b),
+                ];
+                for &(base, extra) in &cases {
+                    let old = add2_pixelwise(base.clone(), extra);
+                    let new = add2(base.clone(), extra);
+                    assert_same(&name, &old, (&new.0, new.1));
+                }
+            }
+        }
+    }
+
+    // Writes a session of whole frames and deltas to a new folder: entry 0
+    // and `keyframes` are whole frames, the rest deltas over the one before.
+    fn write_session(name: &str, frames: &[DynamicImage], keyframes: &[usize]) -> (PathBuf, Vec<Vec<String>>) {
+        let dir = env::temp_dir().join(format!("delta-test-{}-{}", process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let mut timings = vec![];
+        for (n, frame) in frames.iter().enumerate() {
+            let file = format!("slide{:03}.png", n);
+            let mut entry = vec![format!("{}.000", n), file.clone()];
+            let image = if n == 0 || keyframes.contains(&n) {
+                entry.push(manifest::tag("keyframe", "1"));
+                keyframe(frame)
+            } else {
+                (*diff2(&frames[n - 1], frame).0).clone()
+            };
+            image.to_rgba().save(dir.join(&file)).unwrap();
+            timings.push(entry);
+        }
+        (dir, timings)
+    }
+
+    #[test]
+    fn corrupt_delta_fails_until_the_next_keyframe() {
+        let frames: Vec<DynamicImage> = (0..6).map(|n| frames(12, 7, false, n).1).collect();
+        let (dir, timings) = write_session("corrupt", &frames, &[3]);
+        fs::write(dir.join(&timings[1][1]), b"not a png").unwrap();
+
+        let mut emitted = vec![];
+        let rebuilt = reconstruct(&dir, &timings, &Selection::All, |index, _, frame| {
+            assert!(**frame == *frames[index].to_rgb(), "entry {} rebuilt wrong", index);
+            emitted.push(index);
+            Ok(())
+        }).unwrap();
+        assert_eq!(emitted, vec![0, 3, 4, 5]);
+        assert_eq!(rebuilt.emitted, 4);
+        let failed: Vec<usize> = rebuilt.failed.iter().map(|f| f.0).collect();
+        assert_eq!(failed, vec![1, 2]);
+
+        // Entries after the keyframe don't need the broken one at all.
+        let rebuilt = reconstruct(&dir, &timings, &Selection::Index(5), |_, _, _| Ok(())).unwrap();
+        assert_eq!((rebuilt.emitted, rebuilt.failed.len()), (1, 0));
+        let rebuilt = reconstruct(&dir, &timings, &Selection::Index(2), |_, _, _| Ok(())).unwrap();
+        assert_eq!((rebuilt.emitted, rebuilt.failed.len()), (0, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn patches_of_two_distant_changes() {
+        let base = RgbImage::from_fn(200, 100, |x, y| Rgb([x as u8, y as u8, 80]));
+        let mut changed = base.clone();
+        for (x, y) in (10..14).flat_map(|x| (5..8).map(move |y| (x, y))) {
+            changed.put_pixel(x, y, Rgb([255, 0, 0]));
+        }
+        for (x, y) in (180..185).flat_map(|x| (90..92).map(move |y| (x, y))) {
+            changed.put_pixel(x, y, Rgb([0, 255, 0]));
+        }
+        let (delta, _) = diff2(&ImageRgb8(base.clone()), &ImageRgb8(changed.clone()));
+        let delta = delta.to_rgba();
+
+        let rects = changed_rects(&delta);
+        assert_eq!(
+            rects,
+            vec![Rect { x: 10, y: 5, w: 4, h: 3 }, Rect { x: 180, y: 90, w: 5, h: 2 }]
+        );
+        let mut frame = base;
+        for rect in &rects {
+            let patch = ImageRgba8(crop(&delta, rect));
+            apply_patch(&mut frame, &patch, rect.x as u32, rect.y as u32).unwrap();
+        }
+        assert!(*frame == *changed);
+    }
+
+    // cargo test --release deltabench -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn deltabench() {
+        let (a, b) = frames(3840, 2160, false, 7);
+        let millis = |start: Instant| {
+            let d = start.elapsed();
+            d.as_secs() as f64 * 1_000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+        };
+
+        let start = Instant::now();
+        let old = diff2_pixelwise(&a, &b);
+        let old_time = millis(start);
+        let start = Instant::now();
+        let new = diff2(&a, &b);
+        let new_time = millis(start);
+        assert_same("diff2", &old, (&new.0, new.1));
+        println!("diff2: {:.1} ms -> {:.1} ms ({:.1}x)", old_time, new_time, old_time / new_time.max(0.001));
+
+        let reverse = diff2(&b, &a).0;
+        let start = Instant::now();
+        let old = add2_pixelwise((*new.0).clone(), &reverse);
+        let old_time = millis(start);
+        let start = Instant::now();
+        let new = add2((*new.0).clone(), &reverse);
+        let new_time = millis(start);
+        assert_same("add2", &old, (&new.0, new.1));
+        println!("add2: {:.1} ms -> {:.1} ms ({:.1}x)", old_time, new_time, old_time / new_time.max(0.001));
+    }
+}
@@ -0,0 +1,279 @@
+/*
+ * Detects frames that aren't worth keeping: all-black display sleep,
+ * screensavers and lock screens.
+ */
+
+use clap::{Arg, ArgMatches};
+use image;
+use image::GenericImage;
+
+// Per channel difference treated as the same colour (same as imgdedupe).
+const DISTANCE_CUTOFF: i32 = 8;
+
+pub enum Blank {
+    Uniform,
+    LowEntropy,
+    Reference(String),
+}
+
+impl Blank {
+    pub fn name(&self) -> String {
+        match *self {
+            Blank::Uniform => "uniform".to_owned(),
+            Blank::LowEntropy => "entropy".to_owned(),
+            Blank::Reference(ref name) => format!("reference:{}", name),
+        }
+    }
+}
+
+pub struct Reference {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    // RGB
+    pub pixels: Vec<u8>,
+}
+
+pub struct BlankDetector {
+    // Max channel difference from the first pixel for a frame to be uniform.
+    pub uniform_tolerance: Option<u8>,
+    // Frames with a luma histogram entropy below this (in bits) are blank.
+    pub min_entropy: Option<f64>,
+    pub references: Vec<Reference>,
+    // Percent of pixels allowed to differ from a reference (e.g. the clock).
+    pub reference_percent: u64,
+}
+
+impl BlankDetector {
+    // `pixels` are RGB or RGBA, `channels` says which.
+    pub fn check(&self, pixels: &[u8], channels: usize, width: u32, height: u32) -> Option<Blank> {
+        if let Some(tolerance) = self.uniform_tolerance {
+            if is_uniform(pixels, channels, tolerance) {
+                return Some(Blank::Uniform);
+            }
+        }
+        if let Some(min_entropy) = self.min_entropy {
+            if luma_entropy(pixels, channels) < min_entropy {
+                return Some(Blank::LowEntropy);
+            }
+        }
+        for reference in &self.references {
+            if reference.width == width && reference.height == height &&
+                matches_reference(pixels, channels, &reference.pixels, self.reference_percent)
+            {
+                return Some(Blank::Reference(reference.name.clone()));
+            }
+        }
+        None
+    }
+}
+
+pub fn load_reference(path: &str) -> Result<Reference, String> {
+    let img = image::open(path).map_err(|e| format!("Error loading {}: {:?}", path, e))?;
+    let (width, height) = img.dimensions();
+    Ok(Reference {
+        name: path.to_owned(),
+        width: width,
+        height: height,
+        pixels: img.to_rgb().into_raw(),
+    })
+}
+
+pub fn is_uniform(pixels: &[u8], channels: usize, tolerance: u8) -> bool {
+    let first = match pixels.chunks(channels).next() {
+        Some(p) => [p[0], p[1], p[2]],
+        None => return true,
+    };
+    pixels.chunks(channels).all(|p| {
+        (0..3).all(|c| (p[c] as i32 - first[c] as i32).abs() <= tolerance as i32)
+    })
+}
+
+pub fn luma_entropy(pixels: &[u8], channels: usize) -> f64 {
+    let mut histogram = [0u64; 256];
+    let mut total: u64 = 0;
+    for p in pixels.chunks(channels) {
+        let luma = (p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000;
+        histogram[luma as usize] += 1;
+        total += 1;
+    }
+    if total == 0 {
+        return 0.0;
+    }
+
+    histogram
+        .iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = n as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn matches_reference(pixels: &[u8], channels: usize, reference: &[u8], percent: u64) -> bool {
+    let total = (reference.len() / 3) as u64;
+    let allowed = total * percent / 100;
+    let mut different: u64 = 0;
+    for (p, r) in pixels.chunks(channels).zip(reference.chunks(3)) {
+        if (0..3).any(|c| (p[c] as i32 - r[c] as i32).abs() > DISTANCE_CUTOFF) {
+            different += 1;
+            if different > allowed {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// Command line options shared by every tool that can detect blank frames.
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("blank-uniform")
+            .long("blank-uniform")
+            .value_name("TOLERANCE")
+            .help("Treat single colour frames (within TOLERANCE per channel) as blank")
+            .takes_value(true),
+        Arg::with_name("blank-entropy")
+            .long("blank-entropy")
+            .value_name("BITS")
+            .help("Treat frames whose brightness entropy is below BITS as blank")
+            .takes_value(true),
+        Arg::with_name("blank-reference")
+            .long("blank-reference")
+            .value_name("IMAGE")
+            .help("Treat frames that look like IMAGE (e.g. the lock screen) as blank")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("blank-reference-percent")
+            .long("blank-reference-percent")
+            .value_name("PERCENT")
+            .help("Percent of pixels allowed to differ from a reference image (default 2)")
+            .takes_value(true),
+    ]
+}
+
+// None when no detector was asked for.
+pub fn from_matches(matches: &ArgMatches) -> Result<Option<BlankDetector>, String> {
+    let uniform_tolerance = match matches.value_of("blank-uniform") {
+        Some(t) => Some(t.parse().map_err(|_| "Invalid --blank-uniform")?),
+        None => None,
+    };
+    let min_entropy = match matches.value_of("blank-entropy") {
+        Some(t) => Some(t.parse().map_err(|_| "Invalid --blank-entropy")?),
+        None => None,
+    };
+    let reference_percent = match matches.value_of("blank-reference-percent") {
+        Some(t) => t.parse().map_err(|_| "Invalid --blank-reference-percent")?,
+        None => 2,
+    };
+    let mut references = vec![];
+    for path in matches.values_of("blank-reference").into_iter().flat_map(|v| v) {
+        references.push(load_reference(path)?);
+    }
+
+    if uniform_tolerance.is_none() && min_entropy.is_none() && references.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(BlankDetector {
+        uniform_tolerance: uniform_tolerance,
+        min_entropy: min_entropy,
+        references: references,
+        reference_percent: reference_percent,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_uniform, luma_entropy, matches_reference, BlankDetector, Reference};
+
+    // RGBA, every pixel `v` grey.
+    fn flat(v: u8, count: usize) -> Vec<u8> {
+        (0..count).flat_map(|_| vec![v, v, v, 255]).collect()
+    }
+
+    fn detector(references: Vec<Reference>) -> BlankDetector {
+        BlankDetector {
+            uniform_tolerance: Some(4),
+            min_entropy: Some(1.0),
+            references: references,
+            reference_percent: 2,
+        }
+    }
+
+    #[test]
+    fn uniform_with_one_odd_pixel() {
+        let mut pixels = flat(40, 16);
+        pixels[5 * 4 + 1] = 50;
+        assert!(!is_uniform(&pixels, 4, 9));
+        assert!(is_uniform(&pixels, 4, 10));
+        assert!(is_uniform(&pixels, 4, 255));
+
+        // Compared with the first pixel, so the odd one first gives the same.
+        pixels.swap(5 * 4 + 1, 1);
+        let rgb: Vec<u8> = pixels.chunks(4).flat_map(|p| p[..3].to_vec()).collect();
+        assert!(!is_uniform(&rgb, 3, 9));
+        assert!(is_uniform(&rgb, 3, 10));
+    }
+
+    #[test]
+    fn entropy_of_flat_and_noisy_frames() {
+        assert_eq!(luma_entropy(&flat(0, 64), 4), 0.0);
+        assert_eq!(luma_entropy(&flat(200, 64), 4), 0.0);
+        assert_eq!(luma_entropy(&[], 4), 0.0);
+
+        // Every brightness once: 8 bits. Two halves: 1 bit.
+        let noisy: Vec<u8> = (0..256).flat_map(|v| vec![v as u8, v as u8, v as u8]).collect();
+        assert!((luma_entropy(&noisy, 3) - 8.0).abs() < 1e-9);
+        let mut halves = flat(0, 32);
+        halves.extend(flat(255, 32));
+        assert!((luma_entropy(&halves, 4) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reference_match_around_the_percentage() {
+        // 100 pixels at 2% allows 2 to differ.
+        let reference = vec![100u8; 100 * 3];
+        let mut pixels = flat(100, 100);
+        for i in 0..2 {
+            pixels[i * 4] = 109;
+        }
+        // Within DISTANCE_CUTOFF, so not different.
+        pixels[50 * 4 + 2] = 108;
+        assert!(matches_reference(&pixels, 4, &reference, 2));
+
+        pixels[3 * 4] = 109;
+        assert!(!matches_reference(&pixels, 4, &reference, 2));
+        assert!(matches_reference(&pixels, 4, &reference, 3));
+    }
+
+    #[test]
+    fn check_tries_each_detector() {
+        let lock_screen = Reference {
+            name: "lock.png".to_owned(),
+            width: 10,
+            height: 10,
+            pixels: (0..100).flat_map(|v| vec![v as u8, v as u8, v as u8]).collect(),
+        };
+        let detector = detector(vec![lock_screen]);
+        let name = |pixels: &[u8], w: u32, h: u32| detector.check(pixels, 4, w, h).map(|b| b.name());
+
+        assert_eq!(name(&flat(0, 100), 10, 10), Some("uniform".to_owned()));
+
+        // Two colours aren't uniform but are below 1 bit.
+        let mut mostly_black = flat(0, 100);
+        for i in 0..10 {
+            mostly_black[i * 4] = 255;
+        }
+        assert_eq!(name(&mostly_black, 10, 10), Some("entropy".to_owned()));
+
+        let lock: Vec<u8> = (0..100).flat_map(|v| vec![v as u8, v as u8, v as u8, 255]).collect();
+        assert_eq!(name(&lock, 10, 10), Some("reference:lock.png".to_owned()));
+        // Only compared with frames of the same size.
+        assert_eq!(name(&lock, 20, 5), None);
+
+        let other: Vec<u8> = (0..100).flat_map(|v| vec![99 - v as u8, 0, v as u8, 255]).collect();
+        assert_eq!(name(&other, 10, 10), None);
+    }
+}
@@ -0,0 +1,290 @@
+/*
+ * Keeps sensitive windows (password managers, chat, mail) out of recordings.
+ * Matching windows are either filled with a solid colour or pause capture.
+ * Applied to the captured frame before it is compared or sent to be saved.
+ */
+
+use capture::{BGRA8, Frame, WindowInfo};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleField {
+    Title,
+    Class,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleAction {
+    Blackout,
+    Pause,
+}
+
+#[derive(Clone, Debug)]
+pub struct WindowRule {
+    pub field: RuleField,
+    pub action: RuleAction,
+    // Case-insensitive glob ('*' and '?').
+    pub pattern: String,
+}
+
+impl WindowRule {
+    pub fn new(field: RuleField, action: RuleAction, pattern: &str) -> WindowRule {
+        WindowRule {
+            field: field,
+            action: action,
+            pattern: pattern.to_lowercase(),
+        }
+    }
+
+    pub fn matches(&self, window: &WindowInfo) -> bool {
+        let text = match self.field {
+            RuleField::Title => &window.title,
+            RuleField::Class => &window.class,
+        };
+        glob_match(self.pattern.as_bytes(), text.to_lowercase().as_bytes())
+    }
+}
+
+pub enum Exclusion {
+    // Number of windows blanked out (possibly 0).
+    Blanked(usize),
+    // Title of the window that means nothing should be recorded.
+    Pause(String),
+}
+
+pub struct Exclusions {
+    pub rules: Vec<WindowRule>,
+    pub colour: BGRA8,
+}
+
+impl Exclusions {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn apply(&self, frame: &mut Frame, windows: &[WindowInfo]) -> Exclusion {
+        let mut blanked = 0;
+        for window in windows {
+            match self.rules.iter().find(|r| r.matches(window)).map(|r| r.action) {
+                Some(RuleAction::Pause) => return Exclusion::Pause(window.title.clone()),
+                Some(RuleAction::Blackout) => {
+                    fill_rect(frame, window.x, window.y, window.w, window.h, self.colour);
+                    blanked += 1;
+                }
+                None => (),
+            }
+        }
+        Exclusion::Blanked(blanked)
+    }
+}
+
+pub fn parse_colour(hex: &str) -> Option<BGRA8> {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(BGRA8 {
+        r: (value >> 16) as u8,
+        g: (value >> 8) as u8,
+        b: value as u8,
+        a: 255,
+    })
+}
+
+// Fills the part of the rectangle that lies inside the frame.
+pub fn fill_rect(frame: &mut Frame, x: i32, y: i32, w: u32, h: u32, colour: BGRA8) {
+    let x_start = x.max(0) as usize;
+    let y_start = y.max(0) as usize;
+    let x_end = ((x as i64 + w as i64).max(0) as usize).min(frame.w);
+    let y_end = ((y as i64 + h as i64).max(0) as usize).min(frame.h);
+    if x_start >= x_end {
+        return;
+    }
+
+    for row in y_start..y_end {
+        for pixel in &mut frame.pixels[row * frame.w + x_start..row * frame.w + x_end] {
+            *pixel = colour;
+        }
+    }
+    if let Some(ref mut deep) = frame.deep {
+        let widen = |v: u8| (v as u16) << 8 | v as u16;
+        let fill = [widen(colour.r), widen(colour.g), widen(colour.b), widen(colour.a)];
+        for row in y_start..y_end {
+            let start = (row * frame.w + x_start) * 4;
+            let end = (row * frame.w + x_end) * 4;
+            for pixel in deep[start..end].chunks_mut(4) {
+                pixel.copy_from_slice(&fill);
+            }
+        }
+    }
+}
+
+// Two pointer wildcard match: on a mismatch, go back to just after the last
+// '*' and let it swallow one more character. Linear in practice, however
+// many stars there are.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last '*' and of the text it was matched against.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn glob_literals_and_wildcards() {
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+        assert!(matches("keepass", "keepass"));
+        assert!(!matches("keepass", "keepassxc"));
+        assert!(matches("keepass*", "keepassxc"));
+        assert!(matches("*password*", "enter password - firefox"));
+        assert!(!matches("*password*", "enter passw0rd"));
+        assert!(matches("?ail", "mail"));
+        assert!(!matches("?ail", "ail"));
+        assert!(matches("*", ""));
+        assert!(matches("**", "anything"));
+        assert!(matches("a*b*c", "a-x-b-y-c"));
+        assert!(!matches("a*b*c", "a-x-c-y-b"));
+        assert!(matches("*.txt", "notes.txt.txt"));
+    }
+
+    #[test]
+    fn glob_many_stars_is_fast() {
+        // Exponential with backtracking recursion.
+        let pattern = "*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b";
+        let text: String = ::std::iter::repeat('a').take(2000).collect();
+        assert!(!matches(pattern, &text));
+        assert!(matches(pattern, &(text + "b")));
+    }
+
+    #[test]
+    fn rules_ignore_case() {
+        let window = WindowInfo {
+            title: "Inbox - Mail".to_owned(),
+            class: "Thunderbird".to_owned(),
+            x: 0,
+            y: 0,
+            w: 1,
+            h: 1,
+        };
+        assert!(WindowRule::new(RuleField::Title, RuleAction::Blackout, "*MAIL").matches(&window));
+        assert!(WindowRule::new(RuleField::Class, RuleAction::Pause, "thunder*").matches(&window));
+        assert!(!WindowRule::new(RuleField::Class, RuleAction::Pause, "*mail*").matches(&window));
+    }
+
+    fn frame(w: usize, h: usize, deep: bool) -> Frame {
+        let grey = BGRA8 {
+            b: 100,
+            g: 100,
+            r: 100,
+            a: 255,
+        };
+        Frame {
+            w: w,
+            h: h,
+            pixels: vec![grey; w * h],
+            dirty: None,
+            deep: if deep { Some(vec![0; w * h * 4]) } else { None },
+        }
+    }
+
+    #[test]
+    fn fill_rect_clips_to_frame() {
+        let colour = parse_colour("#ff0080").unwrap();
+        let mut f = frame(4, 3, true);
+        let grey = f.pixels[0];
+        // Hangs off the top left corner.
+        fill_rect(&mut f, -2, -1, 4, 3, colour);
+        for y in 0..3 {
+            for x in 0..4 {
+                let expected = if x < 2 && y < 2 { colour } else { grey };
+                assert_eq!(f.pixels[y * 4 + x], expected, "pixel {},{}", x, y);
+            }
+        }
+        let deep = f.deep.as_ref().unwrap();
+        assert_eq!(&deep[0..4], &[0xffff, 0, 0x8080, 0xffff]);
+        assert_eq!(&deep[(1 * 4 + 2) * 4..(1 * 4 + 3) * 4], &[0, 0, 0, 0]);
+
+        // Entirely outside.
+        let mut f = frame(4, 3, false);
+        fill_rect(&mut f, 4, 0, 10, 10, colour);
+        fill_rect(&mut f, -10, 0, 10, 10, colour);
+        fill_rect(&mut f, 0, 3, 10, 10, colour);
+        assert!(f.pixels.iter().all(|&p| p == grey));
+    }
+
+    #[test]
+    fn blackout_and_pause() {
+        let windows = vec![
+            WindowInfo {
+                title: "Terminal".to_owned(),
+                class: "xterm".to_owned(),
+                x: 0,
+                y: 0,
+                w: 2,
+                h: 2,
+            },
+            WindowInfo {
+                title: "KeePassXC".to_owned(),
+                class: "keepassxc".to_owned(),
+                x: 2,
+                y: 1,
+                w: 5,
+                h: 5,
+            },
+        ];
+        let colour = BGRA8 {
+            b: 0,
+            g: 0,
+            r: 0,
+            a: 255,
+        };
+        let mut exclusions = Exclusions {
+            rules: vec![WindowRule::new(RuleField::Class, RuleAction::Blackout, "keepass*")],
+            colour: colour,
+        };
+        let mut f = frame(4, 3, false);
+        match exclusions.apply(&mut f, &windows) {
+            Exclusion::Blanked(n) => assert_eq!(n, 1),
+            Exclusion::Pause(_) => panic!("paused"),
+        }
+        let blanked: Vec<bool> = f.pixels.iter().map(|&p| p == colour).collect();
+        assert_eq!(
+            blanked,
+            vec![false, false, false, false, false, false, true, true, false, false, true, true]
+        );
+
+        exclusions.rules[0].action = RuleAction::Pause;
+        match exclusions.apply(&mut frame(4, 3, false), &windows) {
+            Exclusion::Pause(title) => assert_eq!(title, "KeePassXC"),
+            Exclusion::Blanked(_) => panic!("not paused"),
+        }
+    }
+}
@@ -0,0 +1,508 @@
+/*
+ * Transformations applied to every captured frame before it is compared
+ * or saved. Filters are given as specs, in the order they are applied:
+ *   crop=x,y,w,h        keep only this area
+ *   scale=N             shrink by N (e.g. 2 for HiDPI to 1x), averaging pixels
+ *   mask=x,y,w,h[;...]  fill areas with black
+ *   redact=x,y,w,h[;..] pixelate areas into 16 pixel blocks
+ *   redact/N=...        the same with N pixel blocks (a large N gives one
+ *                       flat colour per area)
+ *   grayscale
+ * Coordinates are those of the frame coming into the filter. A composited
+ * pointer goes through the same filters, so it is scaled, masked and
+ * greyed along with the frame.
+ */
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use capture::{BGRA8, Cursor, Frame, Rect};
+use exclude::fill_rect;
+use manifest::parse_rects;
+
+// Default size of the blocks redacted areas are pixelated into.
+const REDACT_BLOCK: usize = 16;
+
+pub trait FrameFilter: Send {
+    fn apply(&self, frame: Frame) -> Frame;
+
+    // Where a point (e.g. the pointer) ends up after filtering.
+    fn map_point(&self, x: i32, y: i32) -> (i32, i32) {
+        (x, y)
+    }
+
+    // The pointer as it should be drawn onto the filtered frame.
+    fn apply_cursor(&self, mut cursor: Cursor) -> Cursor {
+        let (x, y) = self.map_point(cursor.x, cursor.y);
+        cursor.x = x;
+        cursor.y = y;
+        cursor
+    }
+
+    // The spec this filter was made from.
+    fn describe(&self) -> String;
+}
+
+pub struct Crop(pub Rect);
+
+impl FrameFilter for Crop {
+    fn apply(&self, frame: Frame) -> Frame {
+        let x = (self.0.x.max(0) as usize).min(frame.w);
+        let y = (self.0.y.max(0) as usize).min(frame.h);
+        let w = (self.0.w as usize).min(frame.w - x);
+        let h = (self.0.h as usize).min(frame.h - y);
+
+        let mut pixels = Vec::with_capacity(w * h);
+        for row in y..y + h {
+            pixels.extend_from_slice(&frame.pixels[row * frame.w + x..row * frame.w + x + w]);
+        }
+        let dirty = frame.dirty.map(|rects| {
+            rects.iter().filter_map(|r| clip(r, x as i32, y as i32, w as u32, h as u32)).collect()
+        });
+
+        Frame {
+            w: w,
+            h: h,
+            pixels: pixels,
+            dirty: dirty,
+            deep: None,
+        }
+    }
+
+    fn map_point(&self, x: i32, y: i32) -> (i32, i32) {
+        (x - self.0.x, y - self.0.y)
+    }
+
+    fn describe(&self) -> String {
+        format!("crop={},{},{},{}", self.0.x, self.0.y, self.0.w, self.0.h)
+    }
+}
+
+pub struct Scale(pub usize);
+
+impl FrameFilter for Scale {
+    fn apply(&self, frame: Frame) -> Frame {
+        let factor = self.0;
+        let (w, h) = (frame.w / factor, frame.h / factor);
+        let area = (factor * factor) as u32;
+
+        let mut pixels = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = [0u32; 4];
+                for sy in y * factor..(y + 1) * factor {
+                    for p in &frame.pixels[sy * frame.w + x * factor..sy * frame.w + (x + 1) * factor] {
+                        sum[0] += p.b as u32;
+                        sum[1] += p.g as u32;
+                        sum[2] += p.r as u32;
+                        sum[3] += p.a as u32;
+                    }
+                }
+                pixels.push(BGRA8 {
+                    b: (sum[0] / area) as u8,
+                    g: (sum[1] / area) as u8,
+                    r: (sum[2] / area) as u8,
+                    a: (sum[3] / area) as u8,
+                });
+            }
+        }
+        let dirty = frame.dirty.map(|rects| {
+            rects
+                .iter()
+                .map(|r| {
+                    let f = factor as i32;
+                    let (left, top) = (r.x / f, r.y / f);
+                    Rect {
+                        x: left,
+                        y: top,
+                        w: ((r.x + r.w as i32 + f - 1) / f - left) as u32,
+                        h: ((r.y + r.h as i32 + f - 1) / f - top) as u32,
+                    }
+                })
+                .collect()
+        });
+
+        Frame {
+            w: w,
+            h: h,
+            pixels: pixels,
+            dirty: dirty,
+            deep: None,
+        }
+    }
+
+    fn map_point(&self, x: i32, y: i32) -> (i32, i32) {
+        (x / self.0 as i32, y / self.0 as i32)
+    }
+
+    // Shrinks the pointer image too; the edges are padded with transparency.
+    fn apply_cursor(&self, cursor: Cursor) -> Cursor {
+        let factor = self.0;
+        let (cw, ch) = (cursor.w as usize, cursor.h as usize);
+        let (w, h) = ((cw + factor - 1) / factor, (ch + factor - 1) / factor);
+        let area = (factor * factor) as u32;
+        let mut pixels = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = [0u32; 4];
+                for sy in y * factor..((y + 1) * factor).min(ch) {
+                    for p in &cursor.pixels[sy * cw + x * factor..sy * cw + ((x + 1) * factor).min(cw)] {
+                        sum[0] += p.b as u32;
+                        sum[1] += p.g as u32;
+                        sum[2] += p.r as u32;
+                        sum[3] += p.a as u32;
+                    }
+                }
+                pixels.push(BGRA8 {
+                    b: (sum[0] / area) as u8,
+                    g: (sum[1] / area) as u8,
+                    r: (sum[2] / area) as u8,
+                    a: (sum[3] / area) as u8,
+                });
+            }
+        }
+        let (x, y) = self.map_point(cursor.x, cursor.y);
+        Cursor {
+            x: x,
+            y: y,
+            xhot: cursor.xhot / factor as u32,
+            yhot: cursor.yhot / factor as u32,
+            w: w as u32,
+            h: h as u32,
+            pixels: pixels,
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("scale={}", self.0)
+    }
+}
+
+pub struct Mask(pub Vec<Rect>);
+
+impl FrameFilter for Mask {
+    fn apply(&self, mut frame: Frame) -> Frame {
+        let black = BGRA8 { b: 0, g: 0, r: 0, a: 255 };
+        for r in &self.0 {
+            fill_rect(&mut frame, r.x, r.y, r.w, r.h, black);
+        }
+        frame
+    }
+
+    fn apply_cursor(&self, cursor: Cursor) -> Cursor {
+        hide_cursor_in(&self.0, cursor)
+    }
+
+    fn describe(&self) -> String {
+        format!("mask={}", ::manifest::format_rects(&self.0))
+    }
+}
+
+// Areas and the block size.
+pub struct Redact(pub Vec<Rect>, pub usize);
+
+impl FrameFilter for Redact {
+    fn apply(&self, mut frame: Frame) -> Frame {
+        let block = self.1;
+        for r in &self.0 {
+            let area = match clip(r, 0, 0, frame.w as u32, frame.h as u32) {
+                Some(area) => area,
+                None => continue,
+            };
+            let mut by = area.y as usize;
+            while by < (area.y + area.h as i32) as usize {
+                let block_h = block.min((area.y + area.h as i32) as usize - by);
+                let mut bx = area.x as usize;
+                while bx < (area.x + area.w as i32) as usize {
+                    let block_w = block.min((area.x + area.w as i32) as usize - bx);
+                    pixelate(&mut frame, bx, by, block_w, block_h);
+                    bx += block_w;
+                }
+                by += block_h;
+            }
+        }
+        frame
+    }
+
+    fn apply_cursor(&self, cursor: Cursor) -> Cursor {
+        hide_cursor_in(&self.0, cursor)
+    }
+
+    fn describe(&self) -> String {
+        if self.1 == REDACT_BLOCK {
+            format!("redact={}", ::manifest::format_rects(&self.0))
+        } else {
+            format!("redact/{}={}", self.1, ::manifest::format_rects(&self.0))
+        }
+    }
+}
+
+pub struct Grayscale;
+
+impl FrameFilter for Grayscale {
+    fn apply(&self, mut frame: Frame) -> Frame {
+        for p in &mut frame.pixels {
+            let luma = ((p.r as u32 * 299 + p.g as u32 * 587 + p.b as u32 * 114) / 1000) as u8;
+            *p = BGRA8 { b: luma, g: luma, r: luma, a: p.a };
+        }
+        frame
+    }
+
+    fn apply_cursor(&self, mut cursor: Cursor) -> Cursor {
+        for p in &mut cursor.pixels {
+            let luma = ((p.r as u32 * 299 + p.g as u32 * 587 + p.b as u32 * 114) / 1000) as u8;
+            *p = BGRA8 { b: luma, g: luma, r: luma, a: p.a };
+        }
+        cursor
+    }
+
+    fn describe(&self) -> String {
+        "grayscale".to_owned()
+    }
+}
+
+#[derive(Default)]
+pub struct FilterChain {
+    pub filters: Vec<Box<dyn FrameFilter>>,
+}
+
+impl FilterChain {
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    // Filters work on 8 bit pixels, so a deep copy would no longer match.
+    pub fn apply(&self, mut frame: Frame) -> Frame {
+        if !self.is_empty() {
+            frame.deep = None;
+        }
+        self.filters.iter().fold(frame, |f, filter| filter.apply(f))
+    }
+
+    pub fn map_point(&self, x: i32, y: i32) -> (i32, i32) {
+        self.filters.iter().fold((x, y), |(x, y), filter| filter.map_point(x, y))
+    }
+
+    pub fn apply_cursor(&self, cursor: Cursor) -> Cursor {
+        self.filters.iter().fold(cursor, |c, filter| filter.apply_cursor(c))
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        self.filters.iter().map(|f| f.describe()).collect()
+    }
+
+    pub fn push_spec(&mut self, spec: &str) -> Result<(), String> {
+        self.filters.push(parse(spec)?);
+        Ok(())
+    }
+
+    // One spec per line; blank lines and lines starting with # are skipped.
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let f = File::open(path).map_err(|e| format!("Error opening {}: {:?}", path, e))?;
+        for line in BufReader::new(f).lines() {
+            let line = line.map_err(|e| format!("Error reading {}: {:?}", path, e))?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                self.push_spec(line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn parse(spec: &str) -> Result<Box<dyn FrameFilter>, String> {
+    let mut parts = spec.splitn(2, '=');
+    let name = parts.next().unwrap_or("").trim();
+    // redact/N
+    let (name, block) = match name.find('/') {
+        Some(n) => (&name[..n], Some(&name[n + 1..])),
+        None => (name, None),
+    };
+    if block.is_some() && name != "redact" {
+        return Err(format!("Only redact takes a block size: `{}`", spec));
+    }
+    let value = parts.next().unwrap_or("").trim();
+    let rects = || match parse_rects(value) {
+        Some(ref rects) if !rects.is_empty() => Ok(rects.clone()),
+        _ => Err(format!("Expected x,y,w,h in filter `{}`", spec)),
+    };
+
+    match name {
+        "crop" => {
+            let r = rects()?;
+            if r.len() != 1 {
+                return Err(format!("crop takes one area: `{}`", spec));
+            }
+            Ok(Box::new(Crop(r[0])))
+        }
+        "scale" => match value.parse::<usize>() {
+            Ok(n) if n >= 1 => Ok(Box::new(Scale(n))),
+            _ => Err(format!("scale needs a whole number: `{}`", spec)),
+        },
+        "mask" => Ok(Box::new(Mask(rects()?))),
+        "redact" => {
+            let block = match block.map(|b| b.parse::<usize>()) {
+                None => REDACT_BLOCK,
+                Some(Ok(n)) if n >= 1 => n,
+                _ => return Err(format!("redact/N needs a whole number: `{}`", spec)),
+            };
+            Ok(Box::new(Redact(rects()?, block)))
+        }
+        "grayscale" => Ok(Box::new(Grayscale)),
+        _ => Err(format!("Unknown filter `{}`", spec)),
+    }
+}
+
+fn clip(r: &Rect, x: i32, y: i32, w: u32, h: u32) -> Option<Rect> {
+    let left = r.x.max(x);
+    let top = r.y.max(y);
+    let right = (r.x + r.w as i32).min(x + w as i32);
+    let bottom = (r.y + r.h as i32).min(y + h as i32);
+    if left >= right || top >= bottom {
+        return None;
+    }
+    Some(Rect {
+        x: left - x,
+        y: top - y,
+        w: (right - left) as u32,
+        h: (bottom - top) as u32,
+    })
+}
+
+// Clears the pointer pixels that would be drawn over any of `areas`.
+fn hide_cursor_in(areas: &[Rect], mut cursor: Cursor) -> Cursor {
+    let left = cursor.x - cursor.xhot as i32;
+    let top = cursor.y - cursor.yhot as i32;
+    for r in areas {
+        let area = match clip(r, left, top, cursor.w, cursor.h) {
+            Some(area) => area,
+            None => continue,
+        };
+        for row in area.y as usize..(area.y + area.h as i32) as usize {
+            let start = row * cursor.w as usize + area.x as usize;
+            for p in &mut cursor.pixels[start..start + area.w as usize] {
+                *p = BGRA8 { b: 0, g: 0, r: 0, a: 0 };
+            }
+        }
+    }
+    cursor
+}
+
+fn pixelate(frame: &mut Frame, x: usize, y: usize, w: usize, h: usize) {
+    let mut sum = [0u32; 3];
+    for row in y..y + h {
+        for p in &frame.pixels[row * frame.w + x..row * frame.w + x + w] {
+            sum[0] += p.b as u32;
+            sum[1] += p.g as u32;
+            sum[2] += p.r as u32;
+        }
+    }
+    let n = (w * h) as u32;
+    let average = BGRA8 {
+        b: (sum[0] / n) as u8,
+        g: (sum[1] / n) as u8,
+        r: (sum[2] / n) as u8,
+        a: 255,
+    };
+    for row in y..y + h {
+        for p in &mut frame.pixels[row * frame.w + x..row * frame.w + x + w] {
+            *p = average;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use capture::{self, BGRA8, Cursor, Frame};
+    use super::{parse, FilterChain};
+
+    fn white() -> BGRA8 {
+        BGRA8 {
+            b: 255,
+            g: 255,
+            r: 255,
+            a: 255,
+        }
+    }
+
+    fn black() -> BGRA8 {
+        BGRA8 {
+            b: 0,
+            g: 0,
+            r: 0,
+            a: 255,
+        }
+    }
+
+    fn chain(specs: &[&str]) -> FilterChain {
+        let mut filters = FilterChain::default();
+        for spec in specs {
+            filters.push_spec(spec).unwrap();
+        }
+        filters
+    }
+
+    // A solid white 4x4 pointer with its hotspot in the middle.
+    fn cursor(x: i32, y: i32) -> Cursor {
+        Cursor {
+            x: x,
+            y: y,
+            xhot: 2,
+            yhot: 2,
+            w: 4,
+            h: 4,
+            pixels: vec![white(); 16],
+        }
+    }
+
+    // Black w x h frame with the pointer drawn in.
+    fn drawn(filters: &FilterChain, w: usize, h: usize, x: i32, y: i32) -> Vec<BGRA8> {
+        let frame = filters.apply(Frame {
+            w: w,
+            h: h,
+            pixels: vec![black(); w * h],
+            dirty: None,
+            deep: None,
+        });
+        let mut pixels = frame.pixels;
+        capture::composite_cursor(&mut pixels, frame.w, frame.h, &filters.apply_cursor(cursor(x, y)));
+        pixels
+    }
+
+    #[test]
+    fn cursor_is_scaled_with_the_frame() {
+        let pixels = drawn(&chain(&["scale=2"]), 8, 8, 4, 4);
+        // 4x4 on an 8x8 screen is 2x2 on the 4x4 frame, in the same place.
+        let white_at: Vec<usize> = (0..16).filter(|&i| pixels[i] == white()).collect();
+        assert_eq!(white_at, vec![5, 6, 9, 10]);
+    }
+
+    #[test]
+    fn cursor_is_masked_and_cropped() {
+        let pixels = drawn(&chain(&["mask=0,0,4,8", "crop=2,0,6,8"]), 8, 8, 4, 4);
+        // Left half of the pointer is under the mask; the rest moves with the crop.
+        let white_at: Vec<usize> = (0..48).filter(|&i| pixels[i] == white()).collect();
+        let expected: Vec<usize> = (2..6).flat_map(|y| vec![y * 6 + 2, y * 6 + 3]).collect();
+        assert_eq!(white_at, expected);
+    }
+
+    #[test]
+    fn redact_block_size() {
+        assert_eq!(parse("redact=0,0,4,4").unwrap().describe(), "redact=0,0,4,4");
+        assert_eq!(parse("redact/1000=0,0,4,4").unwrap().describe(), "redact/1000=0,0,4,4");
+        assert!(parse("redact/0=0,0,4,4").is_err());
+        assert!(parse("mask/4=0,0,4,4").is_err());
+
+        // One block over the whole area is a single flat colour.
+        let mut pixels = vec![black(); 16];
+        pixels[0] = white();
+        let frame = chain(&["redact/1000=0,0,4,4"]).apply(Frame {
+            w: 4,
+            h: 4,
+            pixels: pixels,
+            dirty: None,
+            deep: None,
+        });
+        assert!(frame.pixels.iter().all(|&p| p == frame.pixels[0]));
+        assert_eq!(frame.pixels[0].r, 255 / 16);
+    }
+}
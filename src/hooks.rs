@@ -0,0 +1,194 @@
+/*
+ * User commands run when a frame is saved or a session ends (upload, OCR,
+ * notifications...). They run on a small thread pool so capture never waits
+ * for them, and failures are recorded in session.json as "hook_errors".
+ *
+ * Commands are run by the shell with these environment variables, which are
+ * also passed as $1.. for save hooks ($1 path, $2 index, $3 time, $4 folder):
+ *   SCREENSHOT_EVENT        save or session-end
+ *   SCREENSHOT_PATH         saved image
+ *   SCREENSHOT_INDEX        number of the image in the session
+ *   SCREENSHOT_TIME         time of the frame (hh:mm:ss.sss)
+ *   SCREENSHOT_SESSION_DIR  session folder
+ *   SCREENSHOT_FRAMES       images in the session (session-end only)
+ */
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use serde_json::Value;
+use threadpool::ThreadPool;
+use session::Session;
+
+pub struct Hooks {
+    pub on_save: Option<String>,
+    pub on_session_end: Option<String>,
+    pool: ThreadPool,
+    session: Arc<Mutex<Session>>,
+}
+
+impl Hooks {
+    pub fn new(
+        on_save: Option<String>,
+        on_session_end: Option<String>,
+        jobs: usize,
+        session: Arc<Mutex<Session>>,
+    ) -> Hooks {
+        Hooks {
+            on_save: on_save,
+            on_session_end: on_session_end,
+            pool: ThreadPool::new(jobs.max(1)),
+            session: session,
+        }
+    }
+
+    pub fn frame_saved(&self, path: &Path, index: u32, time: &str, dir: &Path) {
+        let command = match self.on_save {
+            Some(ref c) => c.clone(),
+            None => return,
+        };
+        let path_str = path.to_string_lossy().into_owned();
+        let env = vec![
+            ("SCREENSHOT_EVENT", "save".to_owned()),
+            ("SCREENSHOT_PATH", path_str.clone()),
+            ("SCREENSHOT_INDEX", index.to_string()),
+            ("SCREENSHOT_TIME", time.to_owned()),
+            ("SCREENSHOT_SESSION_DIR", dir.to_string_lossy().into_owned()),
+        ];
+        let args = env[1..].iter().map(|&(_, ref v)| v.clone()).collect();
+        self.spawn("save", command, env, args, dir.to_owned(), Some(path_str));
+    }
+
+    pub fn session_ended(&self, dir: &Path, frames: usize) {
+        let command = match self.on_session_end {
+            Some(ref c) => c.clone(),
+            None => return,
+        };
+        let env = vec![
+            ("SCREENSHOT_EVENT", "session-end".to_owned()),
+            ("SCREENSHOT_SESSION_DIR", dir.to_string_lossy().into_owned()),
+            ("SCREENSHOT_FRAMES", frames.to_string()),
+        ];
+        let args = vec![dir.to_string_lossy().into_owned()];
+        self.spawn("session-end", command, env, args, dir.to_owned(), None);
+    }
+
+    // Waits for queued and running hooks.
+    pub fn join(&self) {
+        self.pool.join();
+    }
+
+    fn spawn(
+        &self,
+        hook: &'static str,
+        command: String,
+        env: Vec<(&'static str, String)>,
+        args: Vec<String>,
+        dir: PathBuf,
+        path: Option<String>,
+    ) {
+        let session = self.session.clone();
+        self.pool.execute(move || {
+            let error = match run(&command, &env, &args) {
+                Ok(_) => return,
+                Err(e) => e,
+            };
+            eprintln!("Hook `{}` failed: {}", command, error);
+
+            let mut record = json!({
+                "hook": hook,
+                "command": command,
+                "error": error,
+            });
+            if let Some(path) = path {
+                record["path"] = Value::String(path);
+            }
+            let recorded = session
+                .lock()
+                .map_err(|_| "session lock poisoned".to_owned())
+                .and_then(|mut s| {
+                    s.append_info(&dir, "hook_errors", record)
+                        .map_err(|e| format!("{:?}", e))
+                });
+            if let Err(e) = recorded {
+                eprintln!("Error recording hook failure: {}", e);
+            }
+        });
+    }
+}
+
+fn run(command: &str, env: &[(&str, String)], args: &[String]) -> Result<(), String> {
+    let mut shell = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(command);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(command).arg("sh");
+        c
+    };
+    shell.args(args);
+    for &(key, ref value) in env {
+        shell.env(key, value);
+    }
+
+    match shell.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{}", status)),
+        Err(e) => Err(format!("couldn't run: {:?}", e)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Read;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use serde_json;
+    use serde_json::{Map, Value};
+    use session::Session;
+    use super::Hooks;
+
+    #[test]
+    fn arguments_env_and_failures() {
+        let dir = env::temp_dir().join(format!("hooks-test-{}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let session = Arc::new(Mutex::new(Session::new(&dir).unwrap()));
+        let out = dir.join("hook.txt");
+        let on_save = format!(
+            "echo \"$#|$1|$2|$3|$4|$SCREENSHOT_EVENT|$SCREENSHOT_PATH|$SCREENSHOT_INDEX|\
+             $SCREENSHOT_TIME|$SCREENSHOT_SESSION_DIR\" > '{}'",
+            out.display()
+        );
+        let hooks = Hooks::new(Some(on_save), Some("exit 3".to_owned()), 2, session.clone());
+
+        let frame = dir.join("screenshot004.png");
+        hooks.frame_saved(&frame, 4, "00:00:01.500", &dir);
+        hooks.session_ended(&dir, 5);
+        hooks.join();
+
+        let mut output = String::new();
+        File::open(&out).unwrap().read_to_string(&mut output).unwrap();
+        let (frame, dir_str) = (frame.display().to_string(), dir.display().to_string());
+        let expected = vec![
+            "4", &frame, "4", "00:00:01.500", &dir_str,
+            "save", &frame, "4", "00:00:01.500", &dir_str,
+        ].join("|");
+        assert_eq!(output.trim_end(), expected);
+
+        // The failing session-end hook is in session.json, and capture
+        // would have carried on.
+        let info: Map<String, Value> =
+            serde_json::from_reader(File::open(dir.join("session.json")).unwrap()).unwrap();
+        let errors = info["hook_errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["hook"], "session-end");
+        assert_eq!(errors[0]["command"], "exit 3");
+        assert!(errors[0]["error"].as_str().unwrap().contains('3'));
+        assert_eq!(session.lock().unwrap().info["hook_errors"], info["hook_errors"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ * The bit of HTTP/1.0 shared by the preview and metrics servers. Both only
+ * listen on localhost, but a client that stalls or opens lots of
+ * connections still shouldn't be able to tie up threads.
+ */
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// How long a read or write may stall, and how much of a request is read.
+const IO_TIMEOUT_SECS: u64 = 10;
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+// Gives back a connection slot when its thread finishes, even by panicking.
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// One of `max` connection slots counted by `open`, or None once they are all
+// taken, after answering 503 with `busy`.
+pub fn take_slot(stream: &mut TcpStream, open: &Arc<AtomicUsize>, max: usize, busy: &[u8]) -> Option<Slot> {
+    if open.fetch_add(1, Ordering::SeqCst) >= max {
+        open.fetch_sub(1, Ordering::SeqCst);
+        respond(stream, "503 Service Unavailable", "text/plain", busy).ok();
+        return None;
+    }
+    Some(Slot(open.clone()))
+}
+
+// So a stalled client can't hold a thread forever.
+pub fn set_timeouts(stream: &TcpStream) {
+    let timeout = Some(Duration::from_secs(IO_TIMEOUT_SECS));
+    stream.set_read_timeout(timeout).ok();
+    stream.set_write_timeout(timeout).ok();
+}
+
+// Method and path (without the query) of a request.
+pub fn read_request(stream: &TcpStream) -> io::Result<(String, String)> {
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers aren't needed, just read past them.
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("").to_owned();
+    let target = parts.next().unwrap_or("");
+    let path = target.split('?').next().unwrap_or("").to_owned();
+    Ok((method, path))
+}
+
+pub fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use super::read_request;
+
+    // What the server side reads from a client sending `request`.
+    fn request(request: &[u8]) -> (String, String) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        read_request(&server).unwrap()
+    }
+
+    #[test]
+    fn reads_method_and_path() {
+        assert_eq!(
+            request(b"GET /strip.png?t=123 HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n"),
+            ("GET".to_owned(), "/strip.png".to_owned())
+        );
+        assert_eq!(
+            request(b"POST /status HTTP/1.0\r\n\r\n"),
+            ("POST".to_owned(), "/status".to_owned())
+        );
+        assert_eq!(request(b"\r\n\r\n"), (String::new(), String::new()));
+    }
+}
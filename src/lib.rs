@@ -0,0 +1,27 @@
+#[cfg(windows)]
+extern crate dxgcap;
+#[cfg(unix)]
+extern crate xcb;
+extern crate clap;
+extern crate image;
+extern crate byteorder;
+extern crate des;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate threadpool;
+extern crate rayon;
+extern crate fs2;
+
+pub mod capture;
+pub mod delta;
+pub mod detect;
+pub mod exclude;
+pub mod filter;
+pub mod hooks;
+pub mod http;
+pub mod manifest;
+pub mod poll;
+pub mod preview;
+pub mod session;
+pub mod stats;
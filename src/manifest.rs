@@ -0,0 +1,98 @@
+/*
+ * timings.json is a list of entries: [time, image path, extras...].
+ * Extras are "key=value" strings so older readers that only look at the
+ * first two fields keep working.
+ */
+
+use capture::Rect;
+
+pub fn tag(key: &str, value: &str) -> String {
+    format!("{}={}", key, value)
+}
+
+pub fn entry_tag<'a>(entry: &'a [String], key: &str) -> Option<&'a str> {
+    entry.iter().skip(2).filter_map(|e| {
+        let mut parts = e.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(k), Some(v)) if k == key => Some(v),
+            _ => None,
+        }
+    }).next()
+}
+
+pub fn set_entry_tag(entry: &mut Vec<String>, key: &str, value: &str) {
+    let prefix = format!("{}=", key);
+    match entry.iter().skip(2).position(|e| e.starts_with(&prefix)) {
+        Some(n) => entry[n + 2] = tag(key, value),
+        None => entry.push(tag(key, value)),
+    }
+}
+
+pub fn remove_entry_tag(entry: &mut Vec<String>, key: &str) {
+    let prefix = format!("{}=", key);
+    let kept: Vec<String> = entry
+        .iter()
+        .enumerate()
+        .filter(|&(n, e)| n < 2 || !e.starts_with(&prefix))
+        .map(|(_, e)| e.clone())
+        .collect();
+    *entry = kept;
+}
+
+// "x,y,w,h;x,y,w,h"
+pub fn format_rects(rects: &[Rect]) -> String {
+    rects
+        .iter()
+        .map(|r| format!("{},{},{},{}", r.x, r.y, r.w, r.h))
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+pub fn parse_rects(value: &str) -> Option<Vec<Rect>> {
+    value
+        .split(';')
+        .filter(|r| !r.is_empty())
+        .map(|r| {
+            let parts: Vec<&str> = r.split(',').collect();
+            if parts.len() != 4 {
+                return None;
+            }
+            Some(Rect {
+                x: parts[0].parse().ok()?,
+                y: parts[1].parse().ok()?,
+                w: parts[2].parse().ok()?,
+                h: parts[3].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+// Entry times are "hh:mm:ss.sss"; plain seconds and "mm:ss" also parse.
+pub fn parse_frametime(value: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in value.split(':') {
+        seconds = seconds * 60.0 + part.trim().parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+// "path@x,y,w,h;path@x,y,w,h"
+pub fn format_patches(patches: &[(String, Rect)]) -> String {
+    patches
+        .iter()
+        .map(|&(ref path, ref r)| format!("{}@{}", path, format_rects(&[*r])))
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+pub fn parse_patches(value: &str) -> Option<Vec<(String, Rect)>> {
+    value
+        .split(';')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let mut parts = p.rsplitn(2, '@');
+            let rect = parse_rects(parts.next()?)?.pop()?;
+            Some((parts.next()?.to_owned(), rect))
+        })
+        .collect()
+}
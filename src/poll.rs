@@ -0,0 +1,140 @@
+/*
+ * How often keyscreenshot asks the source for a frame. Polling runs at the
+ * fastest rate while the screen is changing; once it has been still for a
+ * while the gap between polls doubles each time nothing changes, down to
+ * the slowest rate. The first change goes straight back to the fastest.
+ * Saves waking the CPU (and battery) for a screen nobody is touching.
+ */
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct PollRate {
+    fastest: Duration,
+    slowest: Duration,
+    // How long the screen has to be unchanged before slowing down.
+    idle_after: Duration,
+    interval: Duration,
+    unchanged_since: Option<Instant>,
+    next_poll: Option<Instant>,
+}
+
+impl PollRate {
+    // Rates are polls per second.
+    pub fn new(max_rate: f64, min_rate: f64, idle_after: Duration) -> PollRate {
+        let fastest = rate_interval(max_rate);
+        PollRate {
+            fastest: fastest,
+            slowest: rate_interval(min_rate).max(fastest),
+            idle_after: idle_after,
+            interval: fastest,
+            unchanged_since: None,
+            next_poll: None,
+        }
+    }
+
+    // Time the source may wait for a change within one poll.
+    pub fn fastest(&self) -> Duration {
+        self.fastest
+    }
+
+    pub fn rate(&self) -> f64 {
+        1.0 / duration_secs(self.interval)
+    }
+
+    // Sleeps until the next poll is due.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if let Some(next_poll) = self.next_poll {
+            if next_poll > now {
+                thread::sleep(next_poll - now);
+            }
+        }
+        self.next_poll = Some(Instant::now() + self.interval);
+    }
+
+    pub fn changed(&mut self) {
+        self.unchanged_since = None;
+        if self.interval != self.fastest {
+            self.set_interval(self.fastest);
+        }
+    }
+
+    pub fn unchanged(&mut self) {
+        let since = *self.unchanged_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= self.idle_after && self.interval < self.slowest {
+            let slower = (self.interval * 2).min(self.slowest);
+            self.set_interval(slower);
+        }
+    }
+
+    fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+        // A change shouldn't wait out a long idle gap.
+        self.next_poll = Some(Instant::now() + interval);
+        println!("Polling at {:.2}/s", self.rate());
+    }
+}
+
+fn rate_interval(rate: f64) -> Duration {
+    Duration::from_millis((1_000.0 / rate.max(0.001)) as u64).max(Duration::from_millis(1))
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use super::PollRate;
+
+    fn assert_rate(poll: &PollRate, rate: f64) {
+        assert!((poll.rate() - rate).abs() < 1e-6, "rate {} != {}", poll.rate(), rate);
+    }
+
+    #[test]
+    fn backs_off_when_idle_and_ramps_up_on_change() {
+        let mut poll = PollRate::new(10.0, 0.5, Duration::from_millis(30));
+        poll.unchanged();
+        poll.unchanged();
+        assert_rate(&poll, 10.0);
+
+        thread::sleep(Duration::from_millis(40));
+        poll.unchanged();
+        assert_rate(&poll, 5.0);
+        for _ in 0..10 {
+            poll.unchanged();
+        }
+        assert_rate(&poll, 0.5);
+
+        poll.changed();
+        assert_rate(&poll, 10.0);
+        // Idle time starts again after a change.
+        poll.unchanged();
+        assert_rate(&poll, 10.0);
+    }
+
+    #[test]
+    fn rates_are_clamped() {
+        // At most 1000 polls a second.
+        let poll = PollRate::new(1e6, 1.0, Duration::from_secs(0));
+        assert_eq!(poll.fastest(), Duration::from_millis(1));
+        assert_rate(&poll, 1000.0);
+
+        // The slowest rate is never faster than the fastest.
+        let mut poll = PollRate::new(2.0, 10.0, Duration::from_secs(0));
+        for _ in 0..5 {
+            poll.unchanged();
+        }
+        assert_rate(&poll, 2.0);
+
+        // A zero slowest rate still polls, every 1000 seconds.
+        let mut poll = PollRate::new(1.0, 0.0, Duration::from_secs(0));
+        for _ in 0..20 {
+            poll.unchanged();
+        }
+        assert_rate(&poll, 0.001);
+    }
+}
@@ -0,0 +1,280 @@
+/*
+ * A small HTTP server for checking on a recording from another machine
+ * (through an SSH tunnel; it only listens on localhost). Everything is read
+ * from the shared Session, so it only ever shows frames that are on disk.
+ *   /              page showing the below, updated by /events
+ *   /latest.png    latest saved frame
+ *   /strip.png     thumbnails of the last few frames, oldest first
+ *   /status        session status as JSON
+ *   /events        server-sent events, one "frame" event per saved frame
+ */
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicUsize;
+use std::thread;
+use std::time::{Duration, Instant};
+use image;
+use image::{DynamicImage, FilterType, GenericImage, ImageBuffer, ImageFormat, Rgb};
+use serde_json::Value;
+use http::{read_request, respond, set_timeouts, take_slot};
+use session::Session;
+
+// Frames in /strip.png and the size each is shrunk to fit.
+const STRIP_FRAMES: usize = 8;
+const THUMBNAIL_SIZE: u32 = 160;
+// How often /events looks for new frames, and sends a keep-alive.
+const EVENT_POLL_MS: u64 = 250;
+const KEEP_ALIVE_SECS: u64 = 15;
+// /events closes after this long; browsers reconnect by themselves, and a
+// viewer that vanished without closing the connection is let go.
+const EVENT_STREAM_SECS: u64 = 600;
+// Viewers served at once.
+const MAX_CONNECTIONS: usize = 16;
+
+const INDEX_PAGE: &'static str = r#"<!DOCTYPE html>
+<html><head><title>keyscreenshot</title></head>
+<body style="font-family: sans-serif">
+<pre id="status"></pre>
+<img id="latest" src="/latest.png" style="max-width: 100%"><br>
+<img id="strip" src="/strip.png">
+<script>
+function refresh() {
+  var t = Date.now();
+  document.getElementById("latest").src = "/latest.png?" + t;
+  document.getElementById("strip").src = "/strip.png?" + t;
+  fetch("/status").then(function (r) { return r.text(); }).then(function (s) {
+    document.getElementById("status").textContent = s;
+  });
+}
+new EventSource("/events").addEventListener("frame", refresh);
+refresh();
+</script>
+</body></html>
+"#;
+
+// Serves on 127.0.0.1:port from a background thread.
+pub fn start(port: u16, session: Arc<Mutex<Session>>) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Preview at http://127.0.0.1:{}/", port);
+    let open = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Preview connection failed: {:?}", e);
+                continue;
+            }
+        };
+        set_timeouts(&stream);
+        let slot = match take_slot(&mut stream, &open, MAX_CONNECTIONS, b"Too many viewers\n") {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let session = session.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = handle(stream, &session) {
+                // Mostly viewers going away mid-response.
+                match e.kind() {
+                    io::ErrorKind::BrokenPipe |
+                    io::ErrorKind::ConnectionReset |
+                    io::ErrorKind::WouldBlock |
+                    io::ErrorKind::TimedOut => (),
+                    _ => eprintln!("Preview request failed: {:?}", e),
+                }
+            }
+        });
+    });
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, session: &Arc<Mutex<Session>>) -> io::Result<()> {
+    let (method, path) = read_request(&stream)?;
+    if method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"GET only\n");
+    }
+
+    match path.as_str() {
+        "/" => respond(&mut stream, "200 OK", "text/html; charset=utf-8", INDEX_PAGE.as_bytes()),
+        "/latest.png" => match latest_frame(session) {
+            Some(path) => {
+                let mut data = vec![];
+                File::open(&path)?.read_to_end(&mut data)?;
+                respond(&mut stream, "200 OK", "image/png", &data)
+            }
+            None => respond(&mut stream, "404 Not Found", "text/plain", b"No frames saved yet\n"),
+        },
+        "/strip.png" => match thumbnail_strip(session) {
+            Some(data) => respond(&mut stream, "200 OK", "image/png", &data),
+            None => respond(&mut stream, "404 Not Found", "text/plain", b"No frames saved yet\n"),
+        },
+        "/status" => {
+            let body = status(session).to_string();
+            respond(&mut stream, "200 OK", "application/json", body.as_bytes())
+        }
+        "/events" => events(&mut stream, session),
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"Not found\n"),
+    }
+}
+
+// Paths of the last `count` distinct frames, oldest first.
+fn recent_frames(session: &Arc<Mutex<Session>>, count: usize) -> Vec<PathBuf> {
+    let session = match session.lock() {
+        Ok(session) => session,
+        Err(_) => return vec![],
+    };
+    let mut paths: Vec<PathBuf> = vec![];
+    for entry in session.timings.iter().rev() {
+        if paths.len() >= count {
+            break;
+        }
+        let path = match entry.get(1) {
+            Some(path) => session.dir.join(path),
+            None => continue,
+        };
+        // Unchanged interval frames reuse the previous file.
+        if paths.last() != Some(&path) {
+            paths.push(path);
+        }
+    }
+    paths.reverse();
+    paths
+}
+
+fn latest_frame(session: &Arc<Mutex<Session>>) -> Option<PathBuf> {
+    recent_frames(session, 1).pop()
+}
+
+fn thumbnail_strip(session: &Arc<Mutex<Session>>) -> Option<Vec<u8>> {
+    let thumbnails: Vec<DynamicImage> = recent_frames(session, STRIP_FRAMES)
+        .iter()
+        .filter_map(|path| image::open(path).ok())
+        .map(|img| img.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle))
+        .collect();
+    if thumbnails.is_empty() {
+        return None;
+    }
+
+    let width: u32 = thumbnails.iter().map(|t| t.width()).sum();
+    let height = thumbnails.iter().map(|t| t.height()).max().unwrap_or(0);
+    let mut strip: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(width, height);
+    let mut x = 0;
+    for thumbnail in &thumbnails {
+        strip.copy_from(&thumbnail.to_rgb(), x, 0);
+        x += thumbnail.width();
+    }
+
+    let mut data = vec![];
+    match DynamicImage::ImageRgb8(strip).save(&mut data, ImageFormat::PNG) {
+        Ok(_) => Some(data),
+        Err(e) => {
+            eprintln!("Error making thumbnail strip: {:?}", e);
+            None
+        }
+    }
+}
+
+fn status(session: &Arc<Mutex<Session>>) -> Value {
+    let session = match session.lock() {
+        Ok(session) => session,
+        Err(_) => return json!({ "error": "session lock poisoned" }),
+    };
+    let last = session.timings.last().map(|entry| {
+        json!({
+            "time": entry.get(0),
+            "path": entry.get(1),
+            "tags": entry.iter().skip(2).collect::<Vec<_>>(),
+        })
+    });
+    json!({
+        "session": session.number,
+        "dir": session.dir.to_string_lossy(),
+        "frames": session.timings.len(),
+        "bytes": session.bytes,
+        "saving": session.in_progress.is_some(),
+        "last": last,
+    })
+}
+
+// Sends an event for every frame added to the session until the viewer
+// disconnects or EVENT_STREAM_SECS pass. A new session starts the count again.
+fn events(stream: &mut TcpStream, session: &Arc<Mutex<Session>>) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n"
+    )?;
+    let frame_count = || {
+        session
+            .lock()
+            .map(|s| (s.number, s.timings.len()))
+            .unwrap_or((0, 0))
+    };
+    let mut seen = frame_count();
+    let mut last_sent = Instant::now();
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(EVENT_STREAM_SECS) {
+        thread::sleep(Duration::from_millis(EVENT_POLL_MS));
+        let now = frame_count();
+        if now != seen {
+            seen = now;
+            let data = status(session).to_string();
+            write!(stream, "event: frame\ndata: {}\n\n", data)?;
+            last_sent = Instant::now();
+        } else if last_sent.elapsed() >= Duration::from_secs(KEEP_ALIVE_SECS) {
+            // Also notices viewers that have gone.
+            write!(stream, ": keep-alive\n\n")?;
+            last_sent = Instant::now();
+        }
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use session::Session;
+    use super::recent_frames;
+
+    #[test]
+    fn recent_frames_skip_reused_files() {
+        let dir = env::temp_dir().join(format!("preview-test-{}", process::id()));
+        let mut session = Session::new(&dir).unwrap();
+        for &(time, path) in &[
+            ("0.000", "screenshot000.png"),
+            ("1.000", "screenshot001.png"),
+            ("2.000", "screenshot001.png"),
+            ("3.000", "screenshot002.png"),
+            ("4.000", "screenshot002.png"),
+        ] {
+            session.timings.push(vec![time.to_owned(), path.to_owned(), "reason=interval".to_owned()]);
+        }
+        let session = Arc::new(Mutex::new(session));
+        let names = |count| -> Vec<PathBuf> {
+            recent_frames(&session, count)
+                .into_iter()
+                .map(|p| p.strip_prefix(&dir).unwrap().to_owned())
+                .collect()
+        };
+
+        assert_eq!(names(2), vec![PathBuf::from("screenshot001.png"), PathBuf::from("screenshot002.png")]);
+        assert_eq!(names(8).len(), 3);
+        assert_eq!(names(1), vec![PathBuf::from("screenshot002.png")]);
+
+        // A new session starts empty, in its own folder.
+        session.lock().unwrap().rotate().unwrap();
+        assert!(recent_frames(&session, 8).is_empty());
+        session.lock().unwrap().timings.push(vec!["5.000".to_owned(), "screenshot000.png".to_owned()]);
+        assert_eq!(recent_frames(&session, 8), vec![dir.join("session002/screenshot000.png")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * Where keyscreenshot is writing to. Shared between the saver thread and
+ * the signal handler, so the handler can always write a timings.json that
+ * matches the images on disk.
+ * Per-frame details go in timings.json, anything about the session as a
+ * whole goes in session.json next to it.
+ */
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_json;
+use serde_json::{Map, Value};
+use fs2;
+
+pub struct Session {
+    pub base: PathBuf,
+    // Starts at 1; later sessions live in base/sessionNNN.
+    pub number: u32,
+    pub dir: PathBuf,
+    pub timings: Vec<Vec<String>>,
+    // Image being written, removed if capture is aborted.
+    pub in_progress: Option<PathBuf>,
+    // Bytes of images saved in this session.
+    pub bytes: u64,
+    // Contents of session.json.
+    pub info: Map<String, Value>,
+    // Options every session starts its session.json with.
+    pub settings: Map<String, Value>,
+}
+
+impl Session {
+    pub fn new(base: &Path) -> io::Result<Session> {
+        fs::create_dir_all(base)?;
+        Ok(Session {
+            base: base.to_owned(),
+            number: 1,
+            dir: base.to_owned(),
+            timings: vec![],
+            in_progress: None,
+            bytes: 0,
+            info: Map::new(),
+            settings: Map::new(),
+        })
+    }
+
+    // Finishes this session and starts the next numbered one.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.write_manifest()?;
+        self.number += 1;
+        self.dir = session_dir(&self.base, self.number);
+        fs::create_dir_all(&self.dir)?;
+        self.timings.clear();
+        self.bytes = 0;
+        self.info = self.settings.clone();
+        println!("Recording to {:?}", self.dir);
+        Ok(())
+    }
+
+    // Written to a temporary file first so timings.json is never half done.
+    pub fn write_manifest(&self) -> io::Result<()> {
+        write_json(&self.dir.join("timings.json"), &self.timings)?;
+        write_json(&self.dir.join("session.json"), &self.info)
+    }
+
+    pub fn set_setting(&mut self, key: &str, value: Value) {
+        self.settings.insert(key.to_owned(), value.clone());
+        self.info.insert(key.to_owned(), value);
+    }
+
+    // Adds to a list in session.json, for this session or an earlier one.
+    pub fn append_info(&mut self, dir: &Path, key: &str, value: Value) -> io::Result<()> {
+        if dir == self.dir.as_path() {
+            push_value(&mut self.info, key, value);
+            return self.write_manifest();
+        }
+
+        let path = dir.join("session.json");
+        let mut info: Map<String, Value> = File::open(&path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+            .unwrap_or_else(Map::new);
+        push_value(&mut info, key, value);
+        write_json(&path, &info)
+    }
+
+    pub fn free_space(&self) -> io::Result<u64> {
+        fs2::available_space(&self.dir)
+    }
+
+    // The free space, if it is below `min_free` bytes.
+    pub fn low_on_space(&self, min_free: u64) -> io::Result<Option<u64>> {
+        let free = self.free_space()?;
+        Ok(if free < min_free { Some(free) } else { None })
+    }
+
+    // For a second Ctrl-C: drop the image being written and record the rest.
+    pub fn abort(&mut self) -> io::Result<()> {
+        if let Some(path) = self.in_progress.take() {
+            fs::remove_file(&path).ok();
+        }
+        self.write_manifest()
+    }
+}
+
+fn push_value(info: &mut Map<String, Value>, key: &str, value: Value) {
+    let list = info.entry(key.to_owned()).or_insert_with(|| Value::Array(vec![]));
+    match *list {
+        Value::Array(ref mut values) => values.push(value),
+        _ => *list = Value::Array(vec![value]),
+    }
+}
+
+fn write_json<T: ::serde::Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    {
+        let f = File::create(&partial)?;
+        serde_json::to_writer(f, value).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    }
+    fs::rename(&partial, path)
+}
+
+pub fn session_dir(base: &Path, number: u32) -> PathBuf {
+    if number <= 1 {
+        base.to_owned()
+    } else {
+        base.join(format!("session{:03}", number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::u64;
+    use serde_json;
+    use super::{session_dir, Session};
+
+    #[test]
+    fn rotation_naming() {
+        let base = env::temp_dir().join(format!("session-rotate-{}", process::id()));
+        fs::remove_dir_all(&base).ok();
+        assert_eq!(session_dir(&base, 1), base);
+        assert_eq!(session_dir(&base, 2), base.join("session002"));
+        assert_eq!(session_dir(&base, 1000), base.join("session1000"));
+
+        let mut session = Session::new(&base).unwrap();
+        session.set_setting("filters", json!(["grayscale"]));
+        session.timings.push(vec!["0.000".to_owned(), "screenshot000.png".to_owned()]);
+        session.bytes = 100;
+        session.rotate().unwrap();
+        session.rotate().unwrap();
+
+        assert_eq!(session.number, 3);
+        assert_eq!(session.dir, base.join("session003"));
+        assert!(session.timings.is_empty());
+        assert_eq!(session.bytes, 0);
+        assert_eq!(session.info.get("filters"), Some(&json!(["grayscale"])));
+        // Each finished session keeps its own timings.
+        let first: Vec<Vec<String>> =
+            serde_json::from_reader(fs::File::open(base.join("timings.json")).unwrap()).unwrap();
+        assert_eq!(first.len(), 1);
+        let second: Vec<Vec<String>> =
+            serde_json::from_reader(fs::File::open(base.join("session002/timings.json")).unwrap()).unwrap();
+        assert!(second.is_empty());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn free_space_guard() {
+        let base = env::temp_dir().join(format!("session-space-{}", process::id()));
+        let session = Session::new(&base).unwrap();
+        let free = session.free_space().unwrap();
+        assert_eq!(session.low_on_space(0).unwrap(), None);
+        assert_eq!(session.low_on_space(free / 2).unwrap(), None);
+        match session.low_on_space(u64::MAX).unwrap() {
+            Some(reported) => assert!(reported < u64::MAX),
+            None => panic!("u64::MAX bytes free"),
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
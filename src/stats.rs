@@ -0,0 +1,258 @@
+/*
+ * Counters for how a recording went, shared by the capture loop and the
+ * saver thread. Counts run from when keyscreenshot started (not per
+ * session) and end up in session.json as "stats"; they can also be
+ * scraped live in Prometheus text format from 127.0.0.1:PORT/metrics.
+ */
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use http::{read_request, respond, set_timeouts, take_slot};
+
+// Scrapes served at once.
+const MAX_CONNECTIONS: usize = 4;
+
+pub struct Stats {
+    started: Instant,
+    // Times the source was asked for a frame.
+    pub polls: AtomicUsize,
+    // Polls that returned a changed frame.
+    pub changes: AtomicUsize,
+    pub timeouts: AtomicUsize,
+    // Capture errors (the source is retried after a pause).
+    pub errors: AtomicUsize,
+    // Frames dropped while a --pause-* window was shown.
+    pub paused: AtomicUsize,
+    // Polls skipped while below --min-free.
+    pub low_space: AtomicUsize,
+    pub saved: AtomicUsize,
+    // Frames not saved: unchanged, blank or reusing the previous file.
+    pub skipped: AtomicUsize,
+    // Frames lost because the image couldn't be written.
+    pub save_errors: AtomicUsize,
+    pub encode_us: AtomicUsize,
+    pub encode_max_us: AtomicUsize,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            polls: AtomicUsize::new(0),
+            changes: AtomicUsize::new(0),
+            timeouts: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            paused: AtomicUsize::new(0),
+            low_space: AtomicUsize::new(0),
+            saved: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            save_errors: AtomicUsize::new(0),
+            encode_us: AtomicUsize::new(0),
+            encode_max_us: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record_encode(&self, took: Duration) {
+        let us = took.as_secs() as usize * 1_000_000 + took.subsec_nanos() as usize / 1_000;
+        self.encode_us.fetch_add(us, Ordering::Relaxed);
+        // Only this thread raises the maximum, so no compare-and-swap needed.
+        if us > self.encode_max_us.load(Ordering::Relaxed) {
+            self.encode_max_us.store(us, Ordering::Relaxed);
+        }
+    }
+
+    fn counters(&self) -> Vec<(&'static str, usize)> {
+        let get = |c: &AtomicUsize| c.load(Ordering::Relaxed);
+        vec![
+            ("polls", get(&self.polls)),
+            ("changes", get(&self.changes)),
+            ("timeouts", get(&self.timeouts)),
+            ("errors", get(&self.errors)),
+            ("paused", get(&self.paused)),
+            ("low_space", get(&self.low_space)),
+            ("saved", get(&self.saved)),
+            ("skipped", get(&self.skipped)),
+            ("save_errors", get(&self.save_errors)),
+        ]
+    }
+
+    fn encode_secs(&self) -> (f64, f64) {
+        (
+            self.encode_us.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+            self.encode_max_us.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        )
+    }
+
+    fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut summary = json!({});
+        for (name, value) in self.counters() {
+            summary[name] = json!(value);
+        }
+        let (total, max) = self.encode_secs();
+        let saved = self.saved.load(Ordering::Relaxed);
+        summary["seconds"] = json!(self.uptime_secs());
+        summary["encode_seconds"] = json!(total);
+        summary["encode_max_seconds"] = json!(max);
+        summary["encode_mean_seconds"] = json!(if saved > 0 { total / saved as f64 } else { 0.0 });
+        summary
+    }
+
+    pub fn status_line(&self, poll_rate: f64) -> String {
+        let get = |c: &AtomicUsize| c.load(Ordering::Relaxed);
+        let saved = get(&self.saved);
+        let (total, _) = self.encode_secs();
+        format!(
+            "[{}s] {:.2} polls/s, {} polls, {} changes, {} timeouts, {} errors, {} saved ({:.0} ms/frame), {} skipped, {} lost",
+            self.uptime_secs(),
+            poll_rate,
+            get(&self.polls),
+            get(&self.changes),
+            get(&self.timeouts),
+            get(&self.errors),
+            saved,
+            if saved > 0 { total * 1_000.0 / saved as f64 } else { 0.0 },
+            get(&self.skipped),
+            get(&self.save_errors)
+        )
+    }
+
+    pub fn prometheus(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in self.counters() {
+            text.push_str(&format!(
+                "# TYPE keyscreenshot_{0}_total counter\nkeyscreenshot_{0}_total {1}\n",
+                name,
+                value
+            ));
+        }
+        let (total, max) = self.encode_secs();
+        text.push_str(&format!(
+            "# TYPE keyscreenshot_encode_seconds_total counter\nkeyscreenshot_encode_seconds_total {}\n",
+            total
+        ));
+        text.push_str(&format!(
+            "# TYPE keyscreenshot_encode_max_seconds gauge\nkeyscreenshot_encode_max_seconds {}\n",
+            max
+        ));
+        text.push_str(&format!(
+            "# TYPE keyscreenshot_uptime_seconds gauge\nkeyscreenshot_uptime_seconds {}\n",
+            self.uptime_secs()
+        ));
+        text
+    }
+}
+
+// Serves /metrics on 127.0.0.1:port from a background thread.
+pub fn serve(port: u16, stats: Arc<Stats>) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Metrics at http://127.0.0.1:{}/metrics", port);
+    serve_on(listener, stats);
+    Ok(())
+}
+
+// Each request gets its own thread, so a client that never sends one
+// can't hold up the next scrape, up to MAX_CONNECTIONS at once.
+fn serve_on(listener: TcpListener, stats: Arc<Stats>) {
+    let open = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        set_timeouts(&stream);
+        let slot = match take_slot(&mut stream, &open, MAX_CONNECTIONS, b"Too many connections\n") {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let stats = stats.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            let served = read_request(&stream).and_then(|(_, path)| if path == "/metrics" {
+                let body = stats.prometheus();
+                respond(&mut stream, "200 OK", "text/plain; version=0.0.4", body.as_bytes())
+            } else {
+                respond(&mut stream, "404 Not Found", "text/plain", b"Not found\n")
+            });
+            if let Err(e) = served {
+                eprintln!("Metrics request failed: {:?}", e);
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::{serve_on, Stats, MAX_CONNECTIONS};
+
+    fn scrape(address: SocketAddr) -> io::Result<String> {
+        let mut scrape = TcpStream::connect(address)?;
+        scrape.set_read_timeout(Some(Duration::from_secs(5)))?;
+        scrape.write_all(b"GET /metrics HTTP/1.0\r\n\r\n")?;
+        let mut response = String::new();
+        scrape.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn silent_client_does_not_block_scrapes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let stats = Arc::new(Stats::new());
+        stats.saved.fetch_add(3, Ordering::Relaxed);
+        serve_on(listener, stats);
+
+        // Connects and never sends anything.
+        let _silent = TcpStream::connect(address).unwrap();
+
+        let start = Instant::now();
+        let response = scrape(address).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(response.starts_with("HTTP/1.0 200 OK"), "{}", response);
+        assert!(response.contains("\nkeyscreenshot_saved_total 3\n"), "{}", response);
+    }
+
+    #[test]
+    fn connections_are_capped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve_on(listener, Arc::new(Stats::new()));
+
+        let silent: Vec<TcpStream> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        // Answered straight away, without reading a request.
+        let mut busy = TcpStream::connect(address).unwrap();
+        busy.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 503 Service Unavailable"), "{}", response);
+
+        // Slots are given back when connections close. Until then a busy
+        // answer can come before the request is sent, resetting it.
+        drop(silent);
+        let start = Instant::now();
+        loop {
+            match scrape(address) {
+                Ok(ref response) if response.starts_with("HTTP/1.0 200 OK") => break,
+                response => assert!(start.elapsed() < Duration::from_secs(5), "{:?}", response),
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}